@@ -0,0 +1,136 @@
+//! End-to-end generation throughput: `cargo bench` times one hour of the default
+//! scenario (10 nodes x 100 queries x 10 metrics), from the gaussians to the documents,
+//! and the bulk body the documents are sent in.  The `baseline_` benches time the same
+//! hour generated the way it was before the block sampler, so the two can be compared
+//! on the same machine.
+
+#![feature(test)]
+
+extern crate hotcloud;
+extern crate rand;
+extern crate chrono;
+extern crate test;
+
+use hotcloud::{es, Config, Generator};
+use chrono::{Duration, UTC};
+use chrono::offset::TimeZone;
+use rand::distributions::{Normal, IndependentSample};
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::sync::mpsc::{sync_channel, Receiver, TrySendError};
+use std::thread;
+use test::Bencher;
+
+#[bench]
+fn generate_hour(b: &mut Bencher) {
+    let config = Config::new();
+    let generator = Generator::new(&config).seed(7);
+    let mut timeline = generator.live(0);
+    b.iter(|| timeline.next().unwrap().docs.len());
+}
+
+#[bench]
+fn generate_day(b: &mut Bencher) {
+    let config = Config::new();
+    let generator = Generator::new(&config).seed(7);
+    b.iter(|| generator.timeline().take(24).map(|hour| hour.docs.len()).sum::<usize>());
+}
+
+// The body of a bulk of an hour of documents, as `send_bulk` builds it
+#[bench]
+fn write_bulk_hour(b: &mut Bencher) {
+    let config = Config::new();
+    let docs = Generator::new(&config).seed(7).live(0).next().unwrap().docs;
+    let mut body = Vec::new();
+    b.iter(|| {
+        body.clear();
+        es::write_bulk(&mut body, &docs).unwrap();
+        body.len()
+    });
+}
+
+#[bench]
+fn baseline_generate_hour(b: &mut Bencher) {
+    let config = Config::new();
+    let (rx, distributions) = (baseline_gaussians(), baseline_distributions(&config));
+    let mut hour = 0;
+    b.iter(|| {
+        hour += 1;
+        baseline_hour(&config, hour, &rx, &distributions).len()
+    });
+}
+
+#[bench]
+fn baseline_generate_day(b: &mut Bencher) {
+    let config = Config::new();
+    let (rx, distributions) = (baseline_gaussians(), baseline_distributions(&config));
+    b.iter(|| (0..24).map(|hour| baseline_hour(&config, hour, &rx, &distributions).len()).sum::<usize>());
+}
+
+// The documents of the baseline, with the timestamp formatted for each of them
+#[allow(dead_code)]
+struct BaselineTuple {
+    node: usize,
+    metric: usize,
+    query: usize,
+    hour: String,
+    value: f64,
+    disruption: usize
+}
+
+// The baseline's regular and disrupted (mean, std) of every tuple, looked up by tuple
+type BaselineDistributions = HashMap<(usize, usize, usize), ((usize, usize), (usize, usize))>;
+
+// Gaussians sampled on a helper thread and buffered in a channel, as the baseline did
+fn baseline_gaussians() -> Receiver<f64> {
+    let (tx, rx) = sync_channel(32768);
+    thread::spawn(move || {
+        let normal = Normal::new(0.0, 1.0);
+        loop {
+            match tx.try_send(normal.ind_sample(&mut thread_rng())) {
+                Ok(_) => {},
+                Err(TrySendError::Full(_)) => thread::sleep_ms(50),
+                Err(TrySendError::Disconnected(_)) => return
+            }
+        }
+    });
+    rx
+}
+
+fn baseline_distributions(config: &Config) -> BaselineDistributions {
+    let mut rng = thread_rng();
+    let mut distributions = HashMap::new();
+    for node in 0..config.nodes {
+        for query in 0..config.queries {
+            for metric in 0..config.metrics {
+                let regular = (rng.gen_range(0, 100), rng.gen_range(1, 10));
+                let disrupted = (rng.gen_range(0, 100), rng.gen_range(1, 10));
+                distributions.insert((node, query, metric), (regular, disrupted));
+            }
+        }
+    }
+    distributions
+}
+
+// One undisrupted hour of the baseline: one `recv()` and one lookup per tuple
+fn baseline_hour(config: &Config, hour: usize, rx: &Receiver<f64>, distributions: &BaselineDistributions) -> Vec<BaselineTuple> {
+    let mut docs = Vec::with_capacity(config.nodes * config.queries * config.metrics);
+    for node in 0..config.nodes {
+        for query in 0..config.queries {
+            for metric in 0..config.metrics {
+                let &((mean, std), _) = distributions.get(&(node, query, metric)).unwrap();
+                let value = (rx.recv().unwrap() * std as f64) + mean as f64;
+                let timestamp = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64);
+                docs.push(BaselineTuple {
+                    node: node,
+                    metric: metric,
+                    query: query,
+                    hour: timestamp.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                    value: value,
+                    disruption: 0
+                });
+            }
+        }
+    }
+    docs
+}
@@ -0,0 +1,164 @@
+use config::Config;
+use detect::{self, Series};
+use model::Model;
+use score::Scorer;
+use query::{HotcloudResult, SearchParams};
+use es::{Agg, Cluster, Doc, Filter, Search};
+use rustc_serialize::json;
+use std::cmp;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+// The most buckets a search may return: `search.max_buckets`, which 7.x and later default
+// to 10,000 or more and 2.x doesn't limit
+const MAX_BUCKETS: usize = 10000;
+
+/// A query that drove the hotcloud value of a metric/hour, and the nodes behind it
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct Contributor {
+    pub query: usize,
+    pub surprise: f64,
+    pub nodes: Vec<NodeContributor>
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct NodeContributor {
+    pub node: usize,
+    pub surprise: f64
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodesResponse {
+    pub aggregations: NodesAggs
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodesAggs {
+    pub metrics: NodesMetrics
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodesMetrics {
+    pub buckets: Vec<NodesMetricBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodesMetricBucket {
+    pub key: usize,
+    pub queries: NodesQueries
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodesQueries {
+    pub buckets: Vec<NodesQueryBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodesQueryBucket {
+    pub key: usize,
+    pub nodes: Nodes
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Nodes {
+    pub buckets: Vec<NodeBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeBucket {
+    pub key: usize,
+    pub series: Series
+}
+
+/// The `n` queries with the largest surprise, largest first
+pub fn top_queries(surprises: Vec<(usize, f64)>, n: usize) -> Vec<Contributor> {
+    let mut surprises = surprises;
+    surprises.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    surprises.truncate(n);
+
+    surprises.into_iter().map(|(query, surprise)| {
+        Contributor {
+            query: query,
+            surprise: surprise,
+            nodes: vec![]
+        }
+    }).collect()
+}
+
+// Fill in the nodes behind each top query.  The (metric,query) series are broken down
+// per node and scored the same way as the queries themselves.  The queries are split
+// between as many searches as it takes to stay under MAX_BUCKETS.  Fails if any of them
+// does, rather than passing the queries off as having no node that stood out
+pub fn attribute_nodes(es: &Arc<Cluster>, config: &Config, model: &Model, scorer: &Scorer,
+                       hour: usize, results: &mut Vec<HotcloudResult>) -> Result<(), String> {
+    if config.attribution.nodes == 0 {
+        return Ok(());
+    }
+
+    let mut queries: Vec<usize> = results.iter().flat_map(|r| r.top.iter().map(|c| c.query))
+        .collect::<HashSet<usize>>().into_iter().collect();
+    queries.sort();
+
+    // Every query brings a bucket per metric, node and hour of the window
+    let per_query = config.metrics * (1 + config.nodes * (config.detect.window + 2));
+    for chunk in queries.chunks(cmp::max(MAX_BUCKETS / per_query, 1)) {
+        let response = match fetch_nodes(es, config, hour, chunk) {
+            Some(response) => response,
+            None => return Err(format!("the search for the nodes behind queries {:?} failed", chunk))
+        };
+        attribute_response(config, model, scorer, response, results);
+    }
+    Ok(())
+}
+
+// Fill in the nodes of the top queries a breakdown per node covers
+fn attribute_response(config: &Config, model: &Model, scorer: &Scorer, response: NodesResponse,
+                      results: &mut Vec<HotcloudResult>) {
+    for metric in response.aggregations.metrics.buckets {
+        let result = match results.iter_mut().find(|r| r.metric == metric.key) {
+            Some(result) => result,
+            None => continue
+        };
+
+        for query in metric.queries.buckets {
+            let contributor = match result.top.iter_mut().find(|c| c.query == query.key) {
+                Some(contributor) => contributor,
+                None => continue
+            };
+
+            let mut nodes: Vec<NodeContributor> = query.nodes.buckets.iter().filter_map(|node| {
+                let averages: Vec<Option<f64>> = node.series.buckets.iter().map(|b| b.avg.value).collect();
+                detect::largest_surprise(model, scorer, config.detect.window, &averages).map(|surprise| {
+                    NodeContributor {
+                        node: node.key,
+                        surprise: surprise
+                    }
+                })
+            }).collect();
+
+            nodes.sort_by(|a, b| b.surprise.partial_cmp(&a.surprise).unwrap());
+            nodes.truncate(config.attribution.nodes);
+            contributor.nodes = nodes;
+        }
+    }
+}
+
+// Fetch the hourly averages of the given queries, per (metric,query,node)
+fn fetch_nodes(es: &Arc<Cluster>, config: &Config, hour: usize, queries: &[usize]) -> Option<NodesResponse> {
+    let params = SearchParams::new(hour, config.detect.window);
+    let search = Search {
+        filters: vec![
+            params.filter(),
+            Filter::Terms { field: "query", values: queries.iter().cloned().collect() }
+        ],
+        aggs: vec![("metrics", Agg::Terms { field: "metric", size: config.metrics, aggs: vec![
+            ("queries", Agg::Terms { field: "query", size: queries.len(), aggs: vec![
+                ("nodes", Agg::Terms { field: "node", size: config.nodes, aggs: vec![
+                    ("series", detect::averages_agg())
+                ]})
+            ]})
+        ]})]
+    };
+
+    es.search(Doc::Data, &search, "").and_then(|body| json::decode(&body).ok())
+}
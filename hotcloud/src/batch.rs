@@ -0,0 +1,68 @@
+use config::Config;
+use std::cmp;
+use std::sync::Mutex;
+
+// How far adaptive batching shrinks bulks, as a fraction of `es.bulk_size`/`es.bulk_bytes`
+const MIN_SCALE: f64 = 1.0 / 64.0;
+
+/// How a bulk went: how long it took to be indexed, if it was, how many times it had to
+/// be sent again after being rejected or failing to arrive, and how many of its
+/// documents the cluster turned away one by one
+#[derive(Debug, Clone, Copy)]
+pub struct Sent {
+    pub millis: Option<f64>,
+    pub retries: u32,
+    pub rejected: usize
+}
+
+/// The size bulks are cut at, in documents and bytes.  Fixed at `es.bulk_size` and
+/// `es.bulk_bytes`, or with `es.adaptive`, scaled down when bulks are rejected (429) or
+/// take longer than `es.target_latency`, and back up towards them while they come back
+/// well within it
+pub struct BatchSize {
+    docs: usize,
+    bytes: usize,
+    adaptive: bool,
+    target: f64,
+    scale: Mutex<f64>
+}
+
+impl BatchSize {
+    pub fn new(config: &Config) -> BatchSize {
+        BatchSize {
+            docs: config.es.bulk_size,
+            bytes: config.es.bulk_bytes,
+            adaptive: config.es.adaptive,
+            target: config.es.target_latency as f64,
+            scale: Mutex::new(1.0)
+        }
+    }
+
+    /// The most documents and bytes (0 for no limit) the next bulk should hold
+    pub fn limits(&self) -> (usize, usize) {
+        let scale = *self.scale.lock().unwrap();
+        let scaled = |limit: usize| cmp::max((limit as f64 * scale) as usize, 1);
+        (scaled(self.docs), if self.bytes > 0 { scaled(self.bytes) } else { 0 })
+    }
+
+    /// Adapt the size of the next bulks to how this one went.  Rejections halve it, slow
+    /// bulks shrink it by a fifth, and fast ones grow it a tenth at a time
+    pub fn observe(&self, sent: &Sent) {
+        if !self.adaptive {
+            return;
+        }
+
+        let mut scale = self.scale.lock().unwrap();
+        let before = *scale;
+        *scale = match sent.millis {
+            _ if sent.retries > 0 || sent.rejected > 0 => *scale * 0.5,
+            Some(millis) if millis > self.target => *scale * 0.8,
+            Some(millis) if millis < self.target / 2.0 => *scale * 1.1,
+            _ => *scale
+        }.max(MIN_SCALE).min(1.0);
+
+        if *scale != before {
+            debug!("Bulk took {:?}ms with {} retries, scaling bulks to {:.0}%", sent.millis, sent.retries, *scale * 100.0);
+        }
+    }
+}
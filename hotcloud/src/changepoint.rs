@@ -0,0 +1,390 @@
+use config::{Config, ChangePointConfig};
+use detect;
+use query::SearchParams;
+use es::{Cluster, Doc, Document};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::f64::consts::PI;
+use std::cmp;
+use chrono::{Duration, UTC};
+use chrono::offset::TimeZone;
+use shutdown::Shutdown;
+
+// Run lengths with less posterior mass than this are dropped from BOCPD
+const MIN_RUN_PROBABILITY: f64 = 0.000001;
+
+/// A sustained shift in the level of a (metric,query) series
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct ChangePoint {
+    pub metric: usize,
+    pub query: usize,
+    /// The hour the shift is estimated to have started
+    pub hour: String,
+    /// The hour the shift was detected
+    pub detected: String,
+    pub method: String,
+    /// Estimated shift of the mean (new level minus old level)
+    pub magnitude: f64
+}
+
+impl Document for ChangePoint {
+    fn id(&self) -> String {
+        format!("{}_{}_{}", self.detected, self.metric, self.query)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Method {
+    /// Two-sided CUSUM over values standardised against the warmup period
+    Cusum { threshold: f64, drift: f64 },
+    /// Bayesian online change-point detection (Adams & MacKay), normal-gamma model
+    Bocpd { hazard: f64 }
+}
+
+impl Method {
+    pub fn new(config: &ChangePointConfig) -> Result<Method, String> {
+        match &*config.method {
+            "cusum" => Ok(Method::Cusum { threshold: config.threshold, drift: config.drift }),
+            "bocpd" => Ok(Method::Bocpd { hazard: 1.0 / config.hazard }),
+            other => Err(format!("Unknown change-point method [{}], expected cusum or bocpd", other))
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Method::Cusum { .. } => "cusum",
+            Method::Bocpd { .. } => "bocpd"
+        }
+    }
+}
+
+/// Tracks every (metric,query) series, fed one hour at a time
+#[derive(Clone)]
+pub struct ChangePoints {
+    method: Method,
+    warmup: usize,
+    series: HashMap<(usize, usize), SeriesState>
+}
+
+impl ChangePoints {
+    pub fn new(config: &ChangePointConfig) -> Result<ChangePoints, String> {
+        Ok(ChangePoints {
+            method: try!(Method::new(config)),
+            warmup: config.warmup,
+            series: HashMap::new()
+        })
+    }
+
+    /// Feed the averages of a single hour, returning any change points they complete
+    pub fn update(&mut self, hour: usize, averages: Vec<(usize, usize, f64)>) -> Vec<ChangePoint> {
+        let mut found = vec![];
+
+        for (metric, query, value) in averages {
+            let (method, warmup) = (&self.method, self.warmup);
+            let state = self.series.entry((metric, query)).or_insert_with(|| SeriesState::new(method, warmup));
+
+            if let Some((onset, magnitude)) = state.update(hour, value) {
+                debug!("Change point: metric {} query {} at {} (detected {})", metric, query, onset, hour);
+                found.push(ChangePoint {
+                    metric: metric,
+                    query: query,
+                    hour: to_timestamp(onset),
+                    detected: to_timestamp(hour),
+                    method: method.name().to_owned(),
+                    magnitude: magnitude
+                });
+            }
+        }
+
+        found
+    }
+}
+
+// Per-series state: a warmup phase that learns the regular level of the series,
+// followed by the detector proper.  After every change point the new level is
+// learned from scratch
+#[derive(Clone)]
+struct SeriesState {
+    method: Method,
+    warmup: usize,
+    history: Vec<f64>,
+    mean: f64,
+    std: f64,
+    detector: Option<Detector>
+}
+
+#[derive(Clone)]
+enum Detector {
+    Cusum(Cusum),
+    Bocpd(Bocpd)
+}
+
+impl SeriesState {
+    fn new(method: &Method, warmup: usize) -> SeriesState {
+        SeriesState {
+            method: method.clone(),
+            warmup: warmup,
+            history: Vec::with_capacity(warmup),
+            mean: 0.0,
+            std: 1.0,
+            detector: None
+        }
+    }
+
+    // Returns the onset hour and magnitude if this value confirms a change point
+    fn update(&mut self, hour: usize, value: f64) -> Option<(usize, f64)> {
+        if self.detector.is_none() {
+            self.history.push(value);
+            if self.history.len() >= self.warmup {
+                self.start();
+            }
+            return None;
+        }
+
+        // Detectors work in standard deviations of the learned level
+        let z = (value - self.mean) / self.std;
+        let change = match self.detector {
+            Some(Detector::Cusum(ref mut c)) => c.update(hour, z),
+            Some(Detector::Bocpd(ref mut b)) => b.update(hour, z),
+            None => None
+        };
+
+        change.map(|(onset, shift)| {
+            self.detector = None;
+            (onset, shift * self.std)
+        })
+    }
+
+    // Learn the level of the series from the warmup and start the detector
+    fn start(&mut self) {
+        let n = self.history.len() as f64;
+        self.mean = self.history.iter().fold(0f64, |acc, v| acc + v) / n;
+        let variance = self.history.iter().fold(0f64, |acc, v| acc + (v - self.mean) * (v - self.mean)) / n;
+        self.std = if variance > 0.0 { variance.sqrt() } else { 1.0 };
+
+        self.history.clear();
+        self.detector = Some(match self.method {
+            Method::Cusum { threshold, drift } => Detector::Cusum(Cusum::new(threshold, drift)),
+            Method::Bocpd { hazard } => Detector::Bocpd(Bocpd::new(hazard))
+        });
+    }
+}
+
+// Page's CUSUM, in both directions.  The onset is the hour the alarming sum last
+// left zero, the shift is estimated from the sum accumulated since then
+#[derive(Clone)]
+struct Cusum {
+    threshold: f64,
+    drift: f64,
+    high: (f64, usize),
+    low: (f64, usize)
+}
+
+impl Cusum {
+    fn new(threshold: f64, drift: f64) -> Cusum {
+        Cusum {
+            threshold: threshold,
+            drift: drift,
+            high: (0.0, 0),
+            low: (0.0, 0)
+        }
+    }
+
+    fn update(&mut self, hour: usize, z: f64) -> Option<(usize, f64)> {
+        if self.high.0 == 0.0 {
+            self.high.1 = hour;
+        }
+        if self.low.0 == 0.0 {
+            self.low.1 = hour;
+        }
+
+        self.high.0 = (self.high.0 + z - self.drift).max(0.0);
+        self.low.0 = (self.low.0 - z - self.drift).max(0.0);
+
+        if self.high.0 > self.threshold {
+            let hours = (hour - self.high.1 + 1) as f64;
+            Some((self.high.1, self.drift + self.high.0 / hours))
+        } else if self.low.0 > self.threshold {
+            let hours = (hour - self.low.1 + 1) as f64;
+            Some((self.low.1, -(self.drift + self.low.0 / hours)))
+        } else {
+            None
+        }
+    }
+}
+
+// Bayesian online change-point detection with a constant hazard and a normal-gamma
+// prior on the (standardised) values.  Reports a change when the most likely run
+// length collapses, with the shift taken from the posterior mean of the new run
+#[derive(Clone)]
+struct Bocpd {
+    hazard: f64,
+    // Posterior over run lengths, and the normal-gamma parameters for each
+    runs: Vec<f64>,
+    mu: Vec<f64>,
+    kappa: Vec<f64>,
+    alpha: Vec<f64>,
+    beta: Vec<f64>,
+    map_run: usize
+}
+
+impl Bocpd {
+    fn new(hazard: f64) -> Bocpd {
+        Bocpd {
+            hazard: hazard,
+            runs: vec![1.0],
+            mu: vec![0.0],
+            kappa: vec![1.0],
+            alpha: vec![1.0],
+            beta: vec![1.0],
+            map_run: 0
+        }
+    }
+
+    fn update(&mut self, hour: usize, z: f64) -> Option<(usize, f64)> {
+
+        // Student-t predictive probability of z under each run length
+        let predictive: Vec<f64> = (0..self.runs.len()).map(|i| {
+            let (mu, kappa, alpha, beta) = (self.mu[i], self.kappa[i], self.alpha[i], self.beta[i]);
+            student_t(z, 2.0 * alpha, mu, beta * (kappa + 1.0) / (alpha * kappa))
+        }).collect();
+
+        // Either the run grows by one, or it resets to zero
+        let mut runs = Vec::with_capacity(self.runs.len() + 1);
+        let reset = self.runs.iter().zip(&predictive).fold(0f64, |acc, (r, p)| acc + r * p * self.hazard);
+        runs.push(reset);
+        for (r, p) in self.runs.iter().zip(&predictive) {
+            runs.push(r * p * (1.0 - self.hazard));
+        }
+
+        let total = runs.iter().fold(0f64, |acc, r| acc + r);
+        if total <= 0.0 || !total.is_finite() {
+            *self = Bocpd::new(self.hazard);
+            return None;
+        }
+
+        // Update the sufficient statistics, with a fresh prior for the new run
+        let (mut mu, mut kappa, mut alpha, mut beta) = (vec![0.0], vec![1.0], vec![1.0], vec![1.0]);
+        for i in 0..self.runs.len() {
+            mu.push((self.kappa[i] * self.mu[i] + z) / (self.kappa[i] + 1.0));
+            kappa.push(self.kappa[i] + 1.0);
+            alpha.push(self.alpha[i] + 0.5);
+            beta.push(self.beta[i] + self.kappa[i] * (z - self.mu[i]).powi(2) / (2.0 * (self.kappa[i] + 1.0)));
+        }
+
+        // Normalise and drop the long tail of negligible run lengths
+        let mut keep = runs.len();
+        for (i, r) in runs.iter_mut().enumerate() {
+            *r /= total;
+            if *r >= MIN_RUN_PROBABILITY {
+                keep = i + 1;
+            }
+        }
+        runs.truncate(keep);
+        mu.truncate(keep);
+        kappa.truncate(keep);
+        alpha.truncate(keep);
+        beta.truncate(keep);
+
+        let map_run = runs.iter().enumerate().fold((0, 0f64), |best, (i, &r)| {
+            if r > best.1 { (i, r) } else { best }
+        }).0;
+
+        self.runs = runs;
+        self.mu = mu;
+        self.kappa = kappa;
+        self.alpha = alpha;
+        self.beta = beta;
+
+        let collapsed = map_run < self.map_run;
+        self.map_run = map_run;
+
+        if !collapsed {
+            return None;
+        }
+
+        // Run length i covers the latest i values, including this one.  Undo the
+        // prior's pull towards zero to get the mean of those values
+        let run = cmp::min(cmp::max(map_run, 1), self.mu.len() - 1);
+        let shift = match self.kappa[run] > 1.0 {
+            true => self.mu[run] * self.kappa[run] / (self.kappa[run] - 1.0),
+            false => self.mu[run]
+        };
+        Some((hour + 1 - cmp::max(run, 1), shift))
+    }
+}
+
+// Density of a (location, scale^2) Student-t distribution
+fn student_t(x: f64, df: f64, loc: f64, scale2: f64) -> f64 {
+    let t = (x - loc) * (x - loc) / (df * scale2);
+    let norm = ln_gamma((df + 1.0) / 2.0) - ln_gamma(df / 2.0) - 0.5 * (df * PI * scale2).ln();
+    (norm - (df + 1.0) / 2.0 * (1.0 + t).ln()).exp()
+}
+
+// Lanczos approximation of ln(Gamma(x))
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [76.18009172947146, -86.50532032941677, 24.01409824083091,
+                                    -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut series = 1.000000000190015;
+    for (i, c) in COEFFICIENTS.iter().enumerate() {
+        series += c / (x + 1.0 + i as f64);
+    }
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+fn to_timestamp(hour: usize) -> String {
+    (UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64)).format("%Y-%m-%dT%H:%M:%S").to_string()
+}
+
+// Run change-point detection over the whole timeline, hour by hour, and index the
+// change points next to the hotcloud results.  Fails at the first hour whose search, or
+// bulk of change points, fails: the detectors need every hour in turn
+pub fn run_changepoints(es: &Arc<Cluster>, config: &Config, shutdown: &Shutdown) -> Result<(), String> {
+    let mut changepoints = ChangePoints::new(&config.changepoint)
+        .unwrap_or_else(|err| panic!("Invalid [changepoint] config: {}", err));
+
+    debug!("Running change-point detection...");
+    let mut bulk = vec![];
+    for hour in 0..config.hours {
+        // The detectors' state isn't saved, so a resumed run starts them over and
+        // there's no point sending what we have
+        if shutdown.requested() {
+            return Ok(());
+        }
+        let averages = try!(hour_averages(es, config, hour).map_err(|err| format!("hour {}: {}", hour, err)));
+        bulk.extend(changepoints.update(hour, averages));
+
+        if bulk.len() >= 500 {
+            try!(::util::index_bulk(es, Doc::ChangePoint, bulk));
+            bulk = vec![];
+        }
+    }
+
+    try!(::util::index_bulk(es, Doc::ChangePoint, bulk));
+    es.refresh(&[Doc::ChangePoint]);
+    Ok(())
+}
+
+/// The average of every (metric,query) series in a single hour.  Fails if the search
+/// does, rather than passing the hour off as one without data
+pub fn hour_averages(es: &Arc<Cluster>, config: &Config, hour: usize) -> Result<Vec<(usize, usize, f64)>, String> {
+    let response = match detect::fetch_series(es, config, SearchParams::new(hour, 0)) {
+        Some(response) => response,
+        None => return Err("the search for the hourly averages failed".to_owned())
+    };
+
+    let mut averages = vec![];
+    for metric in response.aggregations.metrics.buckets {
+        for query in metric.queries.buckets {
+            for bucket in query.series.buckets {
+                if let Some(avg) = bucket.avg.value {
+                    averages.push((metric.key, query.key, avg));
+                }
+            }
+        }
+    }
+
+    Ok(averages)
+}
@@ -0,0 +1,140 @@
+use config::Config;
+use generator::{Generator, Scheduled};
+use rand;
+use rustc_serialize::json;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// How far a `run` has got, saved as JSON so an interrupted run can pick up from there
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct Progress {
+    /// The settings the generated data depends on, which a resumed run must not change
+    pub scenario: String,
+    /// Seed of the generator, so the remaining hours come out as they would have
+    pub seed: u64,
+    /// The disruptions of the timeline, in order
+    pub disruptions: Vec<Scheduled>,
+    /// Hours of data indexed so far, from the start of the timeline
+    pub generated: usize,
+    /// Whether the change points have been computed and indexed
+    pub changepoints: bool,
+    /// The hours each hotcloud thread scores, empty until the hotcloud starts
+    pub batches: Vec<Batch>
+}
+
+/// A range of hours scored by one hotcloud thread
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, Copy)]
+pub struct Batch {
+    /// The first hour not scored and indexed yet
+    pub next: usize,
+    pub end: usize
+}
+
+/// The progress of a run, saved to `checkpoint.path` every time it is updated
+pub struct Checkpoint {
+    path: String,
+    resumed: bool,
+    progress: Mutex<Progress>
+}
+
+impl Checkpoint {
+    /// Resume from the checkpoint at `checkpoint.path` if there is one, or start a new run
+    pub fn open(config: &Config) -> Result<Checkpoint, String> {
+        let path = &config.checkpoint.path;
+        if path.len() == 0 || !Path::new(path).exists() {
+            return Ok(Checkpoint::start(config, rand::random()));
+        }
+
+        let mut body = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut body))
+                 .map_err(|err| format!("Could not read checkpoint {}: {}", path, err)));
+        let progress: Progress = try!(json::decode(&body)
+                 .map_err(|err| format!("Could not parse checkpoint {}: {}, delete it to start over", path, err)));
+
+        let expected = scenario(config);
+        if progress.scenario != expected {
+            return Err(format!("Checkpoint {} was saved by a different scenario ({}, now {}), delete it to start over",
+                               path, progress.scenario, expected));
+        }
+
+        Ok(Checkpoint {
+            path: path.clone(),
+            resumed: true,
+            progress: Mutex::new(progress)
+        })
+    }
+
+    /// A new run, generated from `seed`
+    pub fn start(config: &Config, seed: u64) -> Checkpoint {
+        let progress = Progress {
+            scenario: scenario(config),
+            seed: seed,
+            disruptions: Generator::new(config).seed(seed).schedule(),
+            generated: 0,
+            changepoints: false,
+            batches: vec![]
+        };
+
+        Checkpoint {
+            path: config.checkpoint.path.clone(),
+            resumed: false,
+            progress: Mutex::new(progress)
+        }
+    }
+
+    /// Whether this run continues an earlier one
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Where the progress is saved, empty if it isn't
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Record some progress, and save it
+    pub fn update<F: FnOnce(&mut Progress)>(&self, update: F) {
+        let mut progress = self.progress.lock().unwrap();
+        update(&mut progress);
+        if self.path.len() == 0 {
+            return;
+        }
+
+        // Written next to the checkpoint and moved over it, so an interruption never
+        // leaves half a checkpoint behind
+        let temp = format!("{}.tmp", self.path);
+        let saved = File::create(&temp)
+            .and_then(|mut file| file.write_all(json::encode(&*progress).unwrap().as_bytes()))
+            .and_then(|_| fs::rename(&temp, &self.path));
+        if let Err(err) = saved {
+            warn!("Could not save checkpoint {}: {}", self.path, err);
+        }
+    }
+
+    /// The run is complete, and the next one starts from scratch
+    pub fn finish(&self) {
+        if self.path.len() > 0 {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// The settings that shape the generated data and the results scored from it.  Whether
+// detection is native or templated isn't one of them, both score the same
+fn scenario(config: &Config) -> String {
+    let detect = &config.detect;
+    format!("nodes={} queries={} metrics={} hours={} disruptions={} regular={:?} disrupted={:?} \
+             model={} window={} alpha={} beta={} gamma={} period={} seasonality={} scorer={} \
+             percentiles={:?} stats={:?} node={:?} attribution={:?} changepoint={:?}",
+            config.nodes, config.queries, config.metrics, config.hours, config.disruptions,
+            config.regular_distribution, config.disrupted_distribution,
+            detect.model, detect.window, detect.alpha, detect.beta, detect.gamma, detect.period,
+            detect.seasonality, detect.scorer, detect.percentiles, detect.stats,
+            config.node, config.attribution, config.changepoint)
+}
@@ -1,29 +1,54 @@
 
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
-use toml::{Parser, Value};
+use std::path::Path;
+use rustc_serialize::Decodable;
+use rustc_serialize::json::Json;
+use toml::{Parser, Table, Value};
 use toml;
 
-#[derive(RustcDecodable, Debug)]
+#[derive(RustcDecodable, RustcEncodable, Debug)]
 pub struct ES {
+    /// Address of the cluster, whose version picks the dialect requests are built in
+    pub url: String,
+    /// Replaces the generated body creating the `data` index, when set.  These three hold
+    /// either the JSON itself or the path of a .json/.mustache file, which is read in its
+    /// place when the config is loaded
     pub mapping: String,
+    /// Replaces the generated body creating the `hotcloud` index, when set
     pub hotcloudmapping: String,
+    /// Replaces the generated source of the `hotcloud` search template, when set
     pub query: String,
-    pub bulk_size: usize
+    /// Documents per bulk of data
+    pub bulk_size: usize,
+    /// Bytes per bulk of data, whichever of the two limits is reached first.  0 for none
+    pub bulk_bytes: usize,
+    /// Shrink bulks below those limits while the cluster is slow or rejecting them, and
+    /// grow them back while it copes
+    pub adaptive: bool,
+    /// Milliseconds a bulk should take at most, with `adaptive`
+    pub target_latency: usize
 }
 
 impl ES {
     fn new() -> ES {
         ES {
+            url: "http://localhost:9200".to_owned(),
             mapping: String::new(),
             hotcloudmapping: String::new(),
             query: String::new(),
-            bulk_size: 10000
+            bulk_size: 10000,
+            bulk_bytes: 10 * 1024 * 1024,
+            adaptive: false,
+            target_latency: 1000
         }
     }
 }
 
-#[derive(RustcDecodable, Debug)]
+#[derive(RustcDecodable, RustcEncodable, Debug)]
 pub struct Distribution {
     pub min_mean: usize,
     pub max_mean: usize,
@@ -55,7 +80,231 @@ impl Distribution {
     }
 }
 
-#[derive(RustcDecodable, Debug)]
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct Stream {
+    /// How many simulated hours pass per wall-clock hour
+    pub speed: f64,
+    /// Hours of history generated immediately before streaming starts
+    pub backfill: usize
+}
+
+impl Stream {
+    fn new() -> Stream {
+        Stream {
+            speed: 1.0,
+            backfill: 48
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct Follow {
+    /// Seconds between polls of the data index for newly completed hours
+    pub poll: usize
+}
+
+impl Follow {
+    fn new() -> Follow {
+        Follow {
+            poll: 10
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct Detect {
+    /// Compute the surprise pipeline in-process rather than with the ES search template
+    pub native: bool,
+    /// Baseline smoothing model: simple, linear, ewma, holt or holt_winters
+    pub model: String,
+    /// Number of hours the baseline is computed over
+    pub window: usize,
+    pub alpha: f64,
+    pub beta: f64,
+    pub gamma: f64,
+    /// Length of a season in hours (holt_winters only)
+    pub period: usize,
+    /// Seasonal component type for holt_winters: add or mult
+    pub seasonality: String,
+    /// How surprise is scored: abs, zscore, mad, percent or log_ratio
+    pub scorer: String,
+    /// Percentiles of the per-query surprises stored per metric/hour.  The first one is
+    /// the headline hotcloud value
+    pub percentiles: Vec<f64>,
+    /// Other statistics of the per-query surprises to store: max and/or mean
+    pub stats: Vec<String>
+}
+
+impl Detect {
+    fn new() -> Detect {
+        Detect {
+            native: false,
+            model: "simple".to_owned(),
+            window: 24,
+            alpha: 0.3,
+            beta: 0.1,
+            gamma: 0.3,
+            period: 24,
+            seasonality: "add".to_owned(),
+            scorer: "abs".to_owned(),
+            percentiles: vec![90.0],
+            stats: vec![]
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct ChangePointConfig {
+    pub enabled: bool,
+    /// Detector to run: cusum or bocpd
+    pub method: String,
+    /// Hours used to learn the regular level of a series, before and after each change
+    pub warmup: usize,
+    /// CUSUM decision interval, in standard deviations
+    pub threshold: f64,
+    /// CUSUM allowance (slack) per hour, in standard deviations
+    pub drift: f64,
+    /// BOCPD expected run length between change points, in hours
+    pub hazard: f64
+}
+
+impl ChangePointConfig {
+    fn new() -> ChangePointConfig {
+        ChangePointConfig {
+            enabled: true,
+            method: "cusum".to_owned(),
+            warmup: 24,
+            threshold: 5.0,
+            drift: 0.5,
+            hazard: 250.0
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct Attribution {
+    /// Number of top contributing queries stored with each metric/hour (0 disables)
+    pub queries: usize,
+    /// Number of top contributing nodes stored with each of those queries (0 disables)
+    pub nodes: usize
+}
+
+impl Attribution {
+    fn new() -> Attribution {
+        Attribution {
+            queries: 5,
+            nodes: 3
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct Node {
+    /// Compute a hotcloud series per node as well as per metric
+    pub enabled: bool,
+    /// Also compute the node x query breakdown, which is far more expensive
+    pub by_query: bool
+}
+
+impl Node {
+    fn new() -> Node {
+        Node {
+            enabled: false,
+            by_query: false
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct Render {
+    /// File the heatmap is written to, as a bare SVG if it ends in .svg, HTML otherwise
+    pub output: String,
+    /// Newline-delimited hotcloud documents to render instead of the `hotcloud` index
+    pub hotcloud: String,
+    /// The generator's JSON output to take the ground truth from instead of the `data` index
+    pub data: String,
+    /// Size of each metric/hour cell, in pixels
+    pub cell_width: usize,
+    pub cell_height: usize
+}
+
+impl Render {
+    fn new() -> Render {
+        Render {
+            output: "hotcloud.html".to_owned(),
+            hotcloud: String::new(),
+            data: String::new(),
+            cell_width: 4,
+            cell_height: 24
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct DashboardConfig {
+    /// Show a live terminal dashboard instead of logging progress
+    pub enabled: bool,
+    /// Milliseconds between redraws
+    pub refresh: usize
+}
+
+impl DashboardConfig {
+    fn new() -> DashboardConfig {
+        DashboardConfig {
+            enabled: false,
+            refresh: 250
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct CheckpointConfig {
+    /// File the progress of `run` is saved to, and resumed from when it exists.  Empty
+    /// disables checkpointing
+    pub path: String,
+    /// Generated hours between checkpoints
+    pub interval: usize
+}
+
+impl CheckpointConfig {
+    fn new() -> CheckpointConfig {
+        CheckpointConfig {
+            path: "hotcloud.checkpoint".to_owned(),
+            interval: 24
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct SummaryConfig {
+    /// File the summary of a run is written to as JSON, when it ends.  Empty disables it
+    pub path: String
+}
+
+impl SummaryConfig {
+    fn new() -> SummaryConfig {
+        SummaryConfig {
+            path: "hotcloud-summary.json".to_owned()
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct Prometheus {
+    /// `host:port` to serve metrics on at `/metrics` during `run`, `stream` and
+    /// `follow`.  Empty disables the endpoint
+    pub address: String
+}
+
+impl Prometheus {
+    fn new() -> Prometheus {
+        Prometheus {
+            address: String::new()
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
 pub struct Config  {
     pub nodes: usize,
     pub queries: usize,
@@ -65,6 +314,17 @@ pub struct Config  {
     pub threads: usize,
     pub regular_distribution: Distribution,
     pub disrupted_distribution: Distribution,
+    pub stream: Stream,
+    pub follow: Follow,
+    pub detect: Detect,
+    pub changepoint: ChangePointConfig,
+    pub attribution: Attribution,
+    pub node: Node,
+    pub render: Render,
+    pub dashboard: DashboardConfig,
+    pub checkpoint: CheckpointConfig,
+    pub summary: SummaryConfig,
+    pub prometheus: Prometheus,
     pub es: ES
 }
 
@@ -75,48 +335,525 @@ impl Config {
     pub fn new() -> Config {
         Config {
             nodes: 10,
-            queries: 10,
+            // Query disruptions hit 1 to queries/10 queries, which needs at least 20
+            queries: 100,
             metrics: 10,
             hours: 3000,
             disruptions: 50,
             threads: 2,
             regular_distribution: Distribution::new(DistributionType::Regular),
             disrupted_distribution: Distribution::new(DistributionType::Disrupted),
+            stream: Stream::new(),
+            follow: Follow::new(),
+            detect: Detect::new(),
+            changepoint: ChangePointConfig::new(),
+            attribution: Attribution::new(),
+            node: Node::new(),
+            render: Render::new(),
+            dashboard: DashboardConfig::new(),
+            checkpoint: CheckpointConfig::new(),
+            summary: SummaryConfig::new(),
+            prometheus: Prometheus::new(),
             es: ES::new()
         }
     }
 
-    pub fn parse(path: String) -> Config {
-        let mut config_toml = String::new();
+    /// Read, decode and validate the config at `path` and the layers above it, collecting
+    /// every problem found rather than stopping at the first
+    pub fn load(path: &str) -> Result<Config, Vec<ConfigError>> {
+        Layers::read(path).and_then(|layers| layers.decode())
+    }
+
+    /// Everything that would make a run fail part-way through, or silently do nothing
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = vec![];
+
+        // The generator picks disruptions, the queries they hit and their distributions
+        // at random, and those ranges must not be empty
+        check(&mut errors, self.nodes >= 1, "nodes", "must be at least 1".to_owned(), "set nodes = 1 or more");
+        check(&mut errors, self.queries >= 20, "queries",
+              format!("is {}, but query disruptions pick between 1 and queries/10 queries, which needs at least 20", self.queries),
+              "set queries = 20 or more");
+        check(&mut errors, self.metrics >= 2, "metrics",
+              format!("is {}, but metric disruptions pick between 1 and metrics-1 metrics, which needs at least 2", self.metrics),
+              "set metrics = 2 or more");
+        check(&mut errors, self.disruptions == 0 || self.hours > 72, "hours",
+              format!("is {}, but disruptions start between hour 48 and 24 hours before the end, which needs more than 72 hours", self.hours),
+              "set hours = 73 or more, or disruptions = 0");
+        check(&mut errors, self.threads >= 1, "threads", "must be at least 1".to_owned(), "set threads = 1 or more");
+        check(&mut errors, self.threads == 0 || self.hours >= self.threads, "hours",
+              format!("is {}, but the timeline is split between {} query threads", self.hours, self.threads),
+              "lower threads to at most hours");
+        for &(name, ref d) in &[("regular_distribution", &self.regular_distribution),
+                                ("disrupted_distribution", &self.disrupted_distribution)] {
+            check(&mut errors, d.min_mean < d.max_mean, &format!("{}.min_mean", name),
+                  format!("is {}, but must be below max_mean ({})", d.min_mean, d.max_mean),
+                  &format!("raise max_mean to at least {}", d.min_mean + 1));
+            check(&mut errors, d.min_std < d.max_std, &format!("{}.min_std", name),
+                  format!("is {}, but must be below max_std ({})", d.min_std, d.max_std),
+                  &format!("raise max_std to at least {}", d.min_std + 1));
+        }
+
+        check(&mut errors, self.stream.speed > 0.0, "stream.speed",
+              format!("is {}, but the simulated clock must move forwards", self.stream.speed), "set speed = 1.0 for real time");
+        check(&mut errors, self.follow.poll >= 1, "follow.poll", "must be at least 1 second".to_owned(), "set poll = 10");
+
+        // Baseline model and scorer
+        let detect = &self.detect;
+        one_of(&mut errors, "detect.model", &detect.model, &["simple", "linear", "ewma", "holt", "holt_winters"]);
+        one_of(&mut errors, "detect.scorer", &detect.scorer, &["abs", "percent", "log_ratio", "zscore", "mad"]);
+        check(&mut errors, detect.window >= 1, "detect.window", "must be at least 1 hour".to_owned(), "set window = 24");
+        if detect.model == "holt_winters" {
+            one_of(&mut errors, "detect.seasonality", &detect.seasonality, &["add", "mult"]);
+            check(&mut errors, detect.period >= 1, "detect.period", "must be at least 1 hour with holt_winters".to_owned(),
+                  "set period = 24 for a daily cycle");
+            check(&mut errors, detect.period == 0 || detect.window >= detect.period * 2, "detect.window",
+                  format!("is {}, but holt_winters needs at least two periods ({})", detect.window, detect.period * 2),
+                  &format!("set window = {}", detect.period * 2));
+        }
+        for &(name, value) in &[("alpha", detect.alpha), ("beta", detect.beta), ("gamma", detect.gamma)] {
+            check(&mut errors, value >= 0.0 && value <= 1.0, &format!("detect.{}", name),
+                  format!("is {}, but smoothing factors are between 0 and 1", value), "pick a value between 0 and 1");
+        }
+        check(&mut errors, detect.percentiles.len() > 0, "detect.percentiles", "must list at least one percentile".to_owned(),
+              "set percentiles = [90.0]");
+        for p in &detect.percentiles {
+            check(&mut errors, *p >= 0.0 && *p <= 100.0, "detect.percentiles",
+                  format!("contains {}, but percentiles are between 0 and 100", p), "remove it or pick a value between 0 and 100");
+        }
+        for stat in &detect.stats {
+            one_of(&mut errors, "detect.stats", stat, &["max", "mean"]);
+        }
+
+        let cp = &self.changepoint;
+        one_of(&mut errors, "changepoint.method", &cp.method, &["cusum", "bocpd"]);
+        check(&mut errors, cp.warmup >= 2, "changepoint.warmup",
+              format!("is {}, but learning a level and its deviation needs at least 2 hours", cp.warmup), "set warmup = 24");
+        check(&mut errors, cp.threshold > 0.0, "changepoint.threshold", format!("is {}, but must be positive", cp.threshold),
+              "set threshold = 5.0");
+        check(&mut errors, cp.drift >= 0.0, "changepoint.drift", format!("is {}, but must not be negative", cp.drift),
+              "set drift = 0.5");
+        check(&mut errors, cp.hazard > 1.0, "changepoint.hazard",
+              format!("is {}, but is the expected run length in hours and must be above 1", cp.hazard), "set hazard = 250.0");
+
+        check(&mut errors, self.render.cell_width >= 1, "render.cell_width", "must be at least 1 pixel".to_owned(), "set cell_width = 4");
+        check(&mut errors, self.render.cell_height >= 1, "render.cell_height", "must be at least 1 pixel".to_owned(), "set cell_height = 24");
+        check(&mut errors, self.dashboard.refresh >= 1, "dashboard.refresh", "must be at least 1 millisecond".to_owned(), "set refresh = 250");
+        check(&mut errors, self.checkpoint.interval >= 1, "checkpoint.interval", "must be at least 1 hour".to_owned(), "set interval = 24");
+
+        check(&mut errors, self.es.url.starts_with("http://") || self.es.url.starts_with("https://"), "es.url",
+              format!("is [{}], but must be an http:// or https:// address", self.es.url), "set url = \"http://localhost:9200\"");
+        check(&mut errors, self.es.bulk_size >= 1, "es.bulk_size", "must be at least 1 document".to_owned(), "set bulk_size = 10000");
+        check(&mut errors, !self.es.adaptive || self.es.target_latency >= 1, "es.target_latency",
+              "must be at least 1 millisecond with adaptive".to_owned(), "set target_latency = 1000");
+
+        errors
+    }
+}
+
+/// Where a setting got its value from
+#[derive(Clone, Debug)]
+pub enum Source {
+    Default,
+    File(String),
+    Env(String)
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Default => write!(f, "default"),
+            Source::File(ref path) => write!(f, "{}", path),
+            Source::Env(ref var) => write!(f, "{}", var)
+        }
+    }
+}
+
+/// The settings of every layer merged together, later layers winning: the defaults, the
+/// base file, the `config.<HOTCLOUD_ENV>.toml` overlay next to it if `HOTCLOUD_ENV` is set,
+/// and finally `HOTCLOUD_*` environment variables (`HOTCLOUD_ES_URL` sets `es.url`)
+pub struct Layers {
+    /// The base file, which the layers are reported against
+    pub path: String,
+    pub table: Table,
+    /// The layer each setting was last set by, keyed by its dotted path
+    pub sources: BTreeMap<String, Source>,
+    // Problems that don't stop the layers from being merged, reported along with the rest
+    errors: Vec<ConfigError>
+}
+
+impl Layers {
+    pub fn read(path: &str) -> Result<Layers, Vec<ConfigError>> {
+        // Anything missing from the files falls back to the defaults, so older
+        // config files keep working as new sections are added
+        let defaults = match toml::encode(&Config::new()) {
+            Value::Table(defaults) => defaults,
+            _ => unreachable!()
+        };
+        let mut layers = Layers {
+            path: path.to_owned(),
+            table: defaults.clone(),
+            sources: BTreeMap::new(),
+            errors: vec![]
+        };
+        layers.record(&defaults, Source::Default);
+
+        let mut paths = vec![path.to_owned()];
+        if let Ok(name) = env::var("HOTCLOUD_ENV") {
+            let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+            paths.push(dir.join(format!("config.{}.toml", name)).to_string_lossy().into_owned());
+        }
+        for path in paths {
+            let toml = try!(read_file(&path));
+            layers.errors.extend(unknown_fields(&defaults, &toml, ""));
+            layers.apply(toml, Source::File(path));
+        }
+
+        for (var, raw) in env::vars() {
+            if !var.starts_with("HOTCLOUD_") || var == "HOTCLOUD_ENV" {
+                continue;
+            }
+            match env_override(&defaults, &var, &raw) {
+                Ok(toml) => layers.apply(toml, Source::Env(var)),
+                Err(err) => layers.errors.push(err)
+            }
+        }
+
+        Ok(layers)
+    }
+
+    fn apply(&mut self, toml: Table, source: Source) {
+        self.record(&toml, source);
+        merge(&mut self.table, toml);
+    }
+
+    fn record(&mut self, toml: &Table, source: Source) {
+        for (field, _) in leaves(toml, "") {
+            self.sources.insert(field, source.clone());
+        }
+    }
+
+    /// Every setting as a dotted path, with its value and where it came from
+    pub fn resolved(&self) -> Vec<(String, &Value, &Source)> {
+        leaves(&self.table, "").into_iter()
+            .map(|(field, value)| {
+                let source = &self.sources[&field];
+                (field, value, source)
+            })
+            .collect()
+    }
+
+    /// Decode and validate the merged settings
+    pub fn decode(self) -> Result<Config, Vec<ConfigError>> {
+        let Layers { path, table, sources, mut errors } = self;
 
-        let mut file = match File::open(&path) {
-            Ok(file) => file,
-            Err(_)  => {
-                error!("Could not find config file, using default!");
-                return Config::new();
+        let config: Option<Config> = match Decodable::decode(&mut toml::Decoder::new(Value::Table(table))) {
+            Ok(config) => {
+                let mut config: Config = config;
+                errors.extend(read_body("es.mapping", &mut config.es.mapping, &sources, &path));
+                errors.extend(read_body("es.hotcloudmapping", &mut config.es.hotcloudmapping, &sources, &path));
+                errors.extend(read_body("es.query", &mut config.es.query, &sources, &path));
+                errors.extend(config.validate());
+                Some(config)
+            },
+            Err(err) => {
+                let field = err.field.clone().unwrap_or(String::new());
+                let kind = toml::DecodeError { field: None, kind: err.kind };
+                errors.push(ConfigError::new(&field, format!("has the wrong type: {}", kind), None));
+                None
             }
         };
 
-        file.read_to_string(&mut config_toml)
-                .unwrap_or_else(|err| panic!("Error while reading config: [{}]", err));
+        // Say which layer set a bad value when it wasn't the base file
+        for err in &mut errors {
+            match sources.get(&err.field) {
+                Some(&Source::Default) | None => {},
+                Some(&Source::File(ref file)) if *file == path => {},
+                Some(source) => err.message = format!("{} (set by {})", err.message, source)
+            }
+        }
 
-        let mut parser = Parser::new(&config_toml);
-        let toml = parser.parse();
+        match (config, errors.len()) {
+            (Some(config), 0) => Ok(config),
+            _ => Err(errors)
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<Table, Vec<ConfigError>> {
+    let mut config_toml = String::new();
+
+    if let Err(err) = File::open(path).and_then(|mut file| file.read_to_string(&mut config_toml)) {
+        return Err(vec![ConfigError::new("", format!("could not read {}: {}", path, err),
+                                         Some("run from the hotcloud directory, or create the file (an empty one uses the defaults)".to_owned()))]);
+    }
+
+    let mut parser = Parser::new(&config_toml);
+    match parser.parse() {
+        Some(toml) => Ok(toml),
+        None => Err(parser.errors.iter().map(|err| {
+            let (loline, locol) = parser.to_linecol(err.lo);
+            let (hiline, hicol) = parser.to_linecol(err.hi);
+            ConfigError::new("", format!("{}:{}:{}-{}:{} {}", path, loline, locol, hiline, hicol, err.desc), None)
+        }).collect())
+    }
+}
+
+// A body given as the path of a .json or .mustache file is replaced by the file, read
+// relative to the config file that named it (or the base file, for a variable).  Either
+// way it has to be JSON, since it is sent as part of a larger request.  The search
+// template only has to be JSON once rendered, so it's checked with its tags filled in
+fn read_body(field: &str, body: &mut String, sources: &BTreeMap<String, Source>, base: &str) -> Option<ConfigError> {
+    if body.len() == 0 {
+        return None;
+    }
+
+    let mut file = None;
+    if body.ends_with(".json") || body.ends_with(".mustache") {
+        let config = match sources.get(field) {
+            Some(&Source::File(ref config)) => config.clone(),
+            _ => base.to_owned()
+        };
+        let path = Path::new(&config).parent().unwrap_or(Path::new("")).join(&*body);
+        let mut contents = String::new();
+        if let Err(err) = File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            return Some(ConfigError::new(field, format!("names {}, which could not be read: {}", path.display(), err),
+                                         Some(format!("paths are relative to {}", config))));
+        }
+        file = Some(path.display().to_string());
+        *body = contents;
+    }
+
+    let rendered = match field {
+        "es.query" => fill_tags(body),
+        _ => body.clone()
+    };
+    match (Json::from_str(&rendered), file) {
+        (Ok(_), _) => None,
+        (Err(err), Some(file)) => {
+            let suggestion = match file.ends_with(".mustache") {
+                true => "it's checked with every {{tag}} replaced by 1 and sections by their contents",
+                false => "fix the file, or remove the setting to use the generated body"
+            };
+            Some(ConfigError::new(field, format!("names {}, which is not valid JSON: {}", file, err), Some(suggestion.to_owned())))
+        },
+        (Err(err), None) => Some(ConfigError::new(field, format!("is not valid JSON: {}", err),
+                                                  Some("fix it, move it to a .json file, or remove it to use the generated body".to_owned())))
+    }
+}
+
+// A mustache template roughly as it renders: every variable (`{{size}}`, `{{{size}}}`)
+// as 1, which reads as JSON quoted or not, and the tags of sections dropped
+fn fill_tags(template: &str) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find("{{") {
+        let close = match rest[open..].find("}}") {
+            Some(close) => open + close,
+            None => break
+        };
+
+        filled.push_str(&rest[..open]);
+        match rest[open + 2..].chars().next() {
+            Some('#') | Some('^') | Some('/') | Some('!') => {},
+            _ => filled.push('1')
+        }
+        rest = &rest[close + 2..];
+        if rest.starts_with('}') {
+            rest = &rest[1..];
+        }
+    }
+
+    filled.push_str(rest);
+    filled
+}
 
-        if toml.is_none() {
-            for err in &parser.errors {
-                let (loline, locol) = parser.to_linecol(err.lo);
-                let (hiline, hicol) = parser.to_linecol(err.hi);
-                println!("{}:{}:{}-{}:{} error: {}",
-                         path, loline, locol, hiline, hicol, err.desc);
+// `HOTCLOUD_ES_BULK_SIZE=500` as the table `{ es = { bulk_size = 500 } }`.  Setting names
+// have underscores of their own, so the variable is matched against the defaults rather
+// than split, and its value is read as the type of the default
+fn env_override(defaults: &Table, var: &str, raw: &str) -> Result<Table, ConfigError> {
+    let name = var["HOTCLOUD_".len()..].to_lowercase();
+    let (path, default) = match env_field(defaults, &name) {
+        Some(found) => found,
+        None => {
+            let fields: Vec<String> = leaves(defaults, "").into_iter().map(|(field, _)| field.replace(".", "_")).collect();
+            let suggestion = match closest(&name, fields.iter().map(|f| &**f)) {
+                Some(field) => format!("did you mean `HOTCLOUD_{}`?", field.to_uppercase()),
+                None => "unset it, or name a setting as HOTCLOUD_<SECTION>_<SETTING>".to_owned()
+            };
+            return Err(ConfigError::new(var, "does not name a setting".to_owned(), Some(suggestion)));
+        }
+    };
+
+    let value = match *default {
+        Value::String(_) => Some(Value::String(raw.to_owned())),
+        _ => Parser::new(&format!("value = {}", raw)).parse().and_then(|mut t| t.remove("value"))
+    };
+    let value = match (default, value) {
+        (&Value::Float(_), Some(Value::Integer(i))) => Value::Float(i as f64),
+        (_, Some(value)) => value,
+        (_, None) => return Err(ConfigError::new(&path.join("."), format!("is set to [{}] by {}, which is not a TOML value", raw, var),
+                                                 Some(format!("expected a value of type `{}`", default.type_str()))))
+    };
+
+    let mut table = Table::new();
+    table.insert(path.last().unwrap().clone(), value);
+    for key in path.iter().rev().skip(1) {
+        let mut parent = Table::new();
+        parent.insert(key.clone(), Value::Table(table));
+        table = parent;
+    }
+    Ok(table)
+}
+
+// The path of the setting `name` spells out with underscores, and its default
+fn env_field<'a>(table: &'a Table, name: &str) -> Option<(Vec<String>, &'a Value)> {
+    for (key, value) in table {
+        match *value {
+            Value::Table(ref section) if name.starts_with(&format!("{}_", key)) => {
+                if let Some((mut path, value)) = env_field(section, &name[key.len() + 1..]) {
+                    path.insert(0, key.clone());
+                    return Some((path, value));
+                }
+            },
+            Value::Table(_) => {},
+            _ if name == key => return Some((vec![key.clone()], value)),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Every value that isn't a table, by dotted path
+fn leaves<'a>(table: &'a Table, prefix: &str) -> Vec<(String, &'a Value)> {
+    let mut found = vec![];
+    for (key, value) in table {
+        let field = format!("{}{}", prefix, key);
+        match *value {
+            Value::Table(ref section) => found.extend(leaves(section, &format!("{}.", field))),
+            _ => found.push((field, value))
+        }
+    }
+    found
+}
+
+/// Something wrong with one field of the config, and how it might be fixed
+#[derive(Debug)]
+pub struct ConfigError {
+    /// The dotted path of the field, empty if the problem is with the file as a whole
+    pub field: String,
+    pub message: String,
+    pub suggestion: Option<String>
+}
+
+impl ConfigError {
+    fn new(field: &str, message: String, suggestion: Option<String>) -> ConfigError {
+        ConfigError {
+            field: field.to_owned(),
+            message: message,
+            suggestion: suggestion
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.field.len() {
+            0 => try!(write!(f, "{}", self.message)),
+            _ => try!(write!(f, "{} {}", self.field, self.message))
+        }
+        match self.suggestion {
+            Some(ref suggestion) => write!(f, "\n    help: {}", suggestion),
+            None => Ok(())
+        }
+    }
+}
+
+/// Print the problems found in the config at `path`
+pub fn report(path: &str, errors: &[ConfigError]) {
+    println!("{} has {} problem{}:", path, errors.len(), if errors.len() == 1 { "" } else { "s" });
+    for err in errors {
+        println!("  {}", err);
+    }
+}
+
+fn check(errors: &mut Vec<ConfigError>, ok: bool, field: &str, message: String, suggestion: &str) {
+    if !ok {
+        errors.push(ConfigError::new(field, message, Some(suggestion.to_owned())));
+    }
+}
+
+// `value` must be one of `options`, and a near miss is probably a typo of the closest
+fn one_of(errors: &mut Vec<ConfigError>, field: &str, value: &str, options: &[&str]) {
+    if options.contains(&value) {
+        return;
+    }
+    let suggestion = match closest(value, options.iter().cloned()) {
+        Some(option) => format!("did you mean `{}`?", option),
+        None => format!("expected one of {}", options.join(", "))
+    };
+    errors.push(ConfigError::new(field, format!("is [{}], which is not known", value), Some(suggestion)));
+}
+
+// Keys of `overlay` that have no default are not read by anything, and are most likely
+// misspelt or in the wrong section
+fn unknown_fields(defaults: &Table, overlay: &Table, prefix: &str) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    for (key, value) in overlay {
+        let field = format!("{}{}", prefix, key);
+        match (defaults.get(key), value) {
+            (Some(&Value::Table(ref d)), &Value::Table(ref o)) => {
+                errors.extend(unknown_fields(d, o, &format!("{}.", field)));
+            },
+            (Some(_), _) => {},
+            (None, _) => {
+                let suggestion = match closest(key, defaults.keys().map(|k| &**k)) {
+                    Some(k) => format!("did you mean `{}{}`?", prefix, k),
+                    None => match prefix.len() {
+                        0 => "remove it, it is not a known setting or section".to_owned(),
+                        _ => format!("remove it, [{}] has no such setting", prefix.trim_right_matches('.'))
+                    }
+                };
+                errors.push(ConfigError::new(&field, "is not a known setting".to_owned(), Some(suggestion)));
             }
-            panic!("Exiting.");
         }
+    }
+    errors
+}
 
-        let config = Value::Table(toml.unwrap());
-        match toml::decode(config) {
-            Some(t) => t,
-            None => panic!("Error while deserializing config")
+// The candidate within a couple of edits of `value`, if any
+fn closest<'a, I: Iterator<Item=&'a str>>(value: &str, candidates: I) -> Option<&'a str> {
+    candidates.map(|c| (distance(value, c), c))
+              .filter(|&(d, c)| d <= 2 && d < c.len())
+              .min_by_key(|&(d, _)| d)
+              .map(|(_, c)| c)
+}
+
+// Levenshtein distance between two strings
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// Recursively overlay `overlay` on top of `base`, table by table
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(&mut Value::Table(ref mut b)), Value::Table(o)) => {
+                merge(b, o);
+            },
+            (_, value) => {
+                base.insert(key, value);
+            }
         }
     }
 }
@@ -0,0 +1,300 @@
+use config::Config;
+use detect;
+use es::Cluster;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use chrono::{DateTime, UTC};
+use termion;
+use termion::color::{self, AnsiValue, Bg};
+use termion::cursor::{self, Goto};
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use shutdown::Shutdown;
+use ::Disruption;
+
+// Scored hours kept around for the heatmap, far more than any terminal is wide
+const KEPT_HOURS: usize = 1024;
+
+// 256-colour heat scale of the heatmap cells, and the colour of a cell without a value
+const HEAT: [u8; 10] = [230, 229, 228, 221, 214, 208, 202, 196, 160, 124];
+const EMPTY: u8 = 236;
+
+/// Progress reported to the dashboard
+pub enum Event {
+    /// The generator finished an hour
+    Generated(usize),
+    /// A bulk of documents reached the data index
+    Indexed(usize),
+    /// A disruption starting at `start` and lasting `length` hours
+    Disrupted { start: usize, length: usize, description: String },
+    /// The hotcloud value of a metric/hour
+    Scored { metric: usize, hour: usize, value: f64 },
+    Finished
+}
+
+/// Where the generator and the queries report their progress.  Without a dashboard the
+/// progress is logged instead
+#[derive(Clone)]
+pub struct Reporter {
+    tx: Option<Sender<Event>>,
+    generated: Arc<AtomicUsize>
+}
+
+impl Reporter {
+    pub fn none() -> Reporter {
+        Reporter {
+            tx: None,
+            generated: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    /// Hours the generator has reported so far
+    pub fn generated(&self) -> usize {
+        self.generated.load(Ordering::SeqCst)
+    }
+
+    pub fn send(&self, event: Event) {
+        if let Event::Generated(_) = event {
+            self.generated.fetch_add(1, Ordering::SeqCst);
+        }
+
+        match self.tx {
+            Some(ref tx) => {
+                let _ = tx.send(event);
+            },
+            None => match event {
+                Event::Generated(hour) => debug!("{}", hour),
+                Event::Disrupted { start, length, description } => {
+                    debug!("Disruption at hour {} for {} hours: {}", start, length, description)
+                },
+                _ => {}
+            }
+        }
+    }
+
+    pub fn disrupted(&self, start: usize, disruption: &(Disruption, usize)) {
+        let description = match disruption.0 {
+            Disruption::Node(node) => format!("node {}", node),
+            Disruption::Query(ref queries) => format!("queries {:?}", queries),
+            Disruption::Metric(ref metrics) => format!("metrics {:?}", metrics)
+        };
+
+        self.send(Event::Disrupted {
+            start: start,
+            length: disruption.1,
+            description: description
+        });
+    }
+}
+
+struct ActiveDisruption {
+    start: usize,
+    length: usize,
+    description: String
+}
+
+// Everything the dashboard shows, built up from the events
+struct State {
+    es: Arc<Cluster>,
+    command: String,
+    hours: Option<usize>,
+    metrics: usize,
+    started: DateTime<UTC>,
+    generated: Option<usize>,
+    indexed: usize,
+    disruptions: Vec<ActiveDisruption>,
+    scored: BTreeMap<usize, Vec<Option<f64>>>,
+    arrivals: VecDeque<usize>,
+    hours_scored: usize,
+    shutdown: Shutdown
+}
+
+impl State {
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Generated(hour) => {
+                self.generated = Some(hour);
+                self.disruptions.retain(|d| d.start + d.length > hour);
+            },
+            Event::Indexed(docs) => self.indexed += docs,
+            Event::Disrupted { start, length, description } => self.disruptions.push(ActiveDisruption {
+                start: start,
+                length: length,
+                description: description
+            }),
+            Event::Scored { metric, hour, value } => {
+                if !self.scored.contains_key(&hour) {
+                    self.arrivals.push_back(hour);
+                    self.hours_scored += 1;
+                    self.scored.insert(hour, vec![None; self.metrics]);
+                    if self.arrivals.len() > KEPT_HOURS {
+                        let oldest = self.arrivals.pop_front().unwrap();
+                        self.scored.remove(&oldest);
+                    }
+                }
+                if let Some(cell) = self.scored.get_mut(&hour).and_then(|row| row.get_mut(metric)) {
+                    *cell = Some(value);
+                }
+            },
+            Event::Finished => {}
+        }
+    }
+
+    fn draw<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let (width, height) = termion::terminal_size().unwrap_or((80, 24));
+        try!(write!(out, "{}{}", termion::clear::All, Goto(1, 1)));
+
+        // Ingest progress
+        let progress = match (self.generated, self.hours) {
+            (Some(hour), Some(hours)) => format!("hour {}/{} ({:.0}%)", hour + 1, hours, (hour + 1) as f64 * 100.0 / hours as f64),
+            (Some(hour), None) => format!("hour {}", hour),
+            (None, _) => "waiting for data".to_owned()
+        };
+        let seconds = (UTC::now() - self.started).num_milliseconds() as f64 / 1000.0;
+        try!(write!(out, "hotcloud {} -- {} -- {} docs indexed ({:.0}/s) -- {} bulks in flight",
+                    self.command, progress, self.indexed, self.indexed as f64 / seconds.max(1.0),
+                    self.es.in_flight.load(Ordering::SeqCst)));
+
+        // Disruptions active at the generator's current hour
+        try!(write!(out, "{}Active disruptions", Goto(1, 3)));
+        let mut line = 4;
+        for d in &self.disruptions {
+            if self.generated.map_or(true, |hour| hour < d.start) {
+                continue;
+            }
+            try!(write!(out, "{}  {}: hours {}-{} ({} left)", Goto(1, line), d.description,
+                        d.start, d.start + d.length - 1, d.start + d.length - 1 - self.generated.unwrap()));
+            line += 1;
+        }
+        if line == 4 {
+            try!(write!(out, "{}  none", Goto(1, line)));
+            line += 1;
+        }
+
+        // The most recently scored hours, oldest on the left
+        let columns = (width as usize).saturating_sub(12);
+        let mut hours: Vec<usize> = self.arrivals.iter().rev().take(columns).cloned().collect();
+        hours.sort();
+
+        let values: Vec<f64> = hours.iter().flat_map(|h| self.scored[h].iter().filter_map(|v| *v)).collect();
+        let top = detect::percentile(&values, 99.0).unwrap_or(1.0);
+        line += 1;
+        match (hours.first(), hours.last()) {
+            (Some(first), Some(last)) => try!(write!(out, "{}Surprise, hours {} to {} (scale 0 - {:.3})", Goto(1, line), first, last, top)),
+            _ => try!(write!(out, "{}Surprise (nothing scored yet)", Goto(1, line)))
+        }
+
+        for metric in 0..self.metrics {
+            line += 1;
+            if line >= height {
+                break;
+            }
+            try!(write!(out, "{}metric {:<4} ", Goto(1, line), metric));
+            for hour in &hours {
+                let colour = match self.scored[hour][metric] {
+                    Some(value) if top > 0.0 => HEAT[((value / top).min(1.0) * (HEAT.len() - 1) as f64) as usize],
+                    Some(_) => HEAT[0],
+                    None => EMPTY
+                };
+                try!(write!(out, "{} ", Bg(AnsiValue(colour))));
+            }
+            try!(write!(out, "{}", Bg(color::Reset)));
+        }
+
+        match self.shutdown.requested() {
+            true => try!(write!(out, "{}stopping, saving progress...", Goto(1, height))),
+            false => try!(write!(out, "{}q: quit", Goto(1, height)))
+        }
+        out.flush()
+    }
+}
+
+/// A live view of a run in the terminal: ingest progress, the active disruptions and a
+/// scrolling heatmap of the hotcloud as it is computed
+pub struct Dashboard {
+    reporter: Reporter,
+    handle: JoinHandle<()>
+}
+
+impl Dashboard {
+
+    /// Take over the terminal.  `hours` is the length of the timeline, if it has one.
+    /// Pressing q requests `shutdown`
+    pub fn start(config: &Config, es: &Arc<Cluster>, command: &str, hours: Option<usize>, shutdown: &Shutdown) -> Dashboard {
+        let (tx, rx) = channel();
+        let mut state = State {
+            es: es.clone(),
+            command: command.to_owned(),
+            hours: hours,
+            metrics: config.metrics,
+            started: UTC::now(),
+            generated: None,
+            indexed: 0,
+            disruptions: vec![],
+            scored: BTreeMap::new(),
+            arrivals: VecDeque::new(),
+            hours_scored: 0,
+            shutdown: shutdown.clone()
+        };
+        let refresh = config.dashboard.refresh as u32;
+
+        let handle = thread::spawn(move || {
+            run(&mut state, rx, refresh);
+        });
+
+        Dashboard {
+            reporter: Reporter {
+                tx: Some(tx),
+                generated: Arc::new(AtomicUsize::new(0))
+            },
+            handle: handle
+        }
+    }
+
+    pub fn reporter(&self) -> Reporter {
+        self.reporter.clone()
+    }
+
+    /// Hand the terminal back once the run is over
+    pub fn finish(self) {
+        self.reporter.send(Event::Finished);
+        let _ = self.handle.join();
+    }
+}
+
+fn run(state: &mut State, rx: Receiver<Event>, refresh: u32) {
+    let mut stdin = termion::async_stdin().bytes();
+    {
+        let raw = io::stdout().into_raw_mode().unwrap_or_else(|err| panic!("Error while starting the dashboard: [{}]", err));
+        let mut screen = AlternateScreen::from(raw);
+        let _ = write!(screen, "{}", cursor::Hide);
+
+        'redraw: loop {
+            while let Ok(event) = rx.try_recv() {
+                if let Event::Finished = event {
+                    break 'redraw;
+                }
+                state.apply(event);
+            }
+
+            // Raw mode swallows Ctrl-C, so it arrives as a key like q.  Either stops the
+            // run cleanly, and the dashboard stays up until it has
+            match stdin.next() {
+                Some(Ok(b'q')) | Some(Ok(3)) => state.shutdown.request(),
+                _ => {}
+            }
+
+            let _ = state.draw(&mut screen);
+            thread::sleep_ms(refresh);
+        }
+
+        let _ = write!(screen, "{}", cursor::Show);
+    }
+
+    println!("hotcloud {} {}: {} docs indexed, {} hours scored", state.command,
+             if state.shutdown.requested() { "stopped" } else { "finished" }, state.indexed, state.hours_scored);
+}
@@ -0,0 +1,176 @@
+use config::Config;
+use model::Model;
+use score::Scorer;
+use query::{HotcloudResult, OptionalValue, SearchParams};
+use attribution;
+use es::{Agg, Cluster, Doc, Search};
+use rustc_serialize::json;
+use std::sync::Arc;
+use std::collections::BTreeMap;
+use chrono::{Duration, UTC};
+use chrono::offset::TimeZone;
+
+#[derive(RustcDecodable, Debug)]
+pub struct SeriesResponse {
+    pub aggregations: SeriesAggs
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct SeriesAggs {
+    pub metrics: SeriesMetrics
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct SeriesMetrics {
+    pub buckets: Vec<SeriesMetricBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct SeriesMetricBucket {
+    pub key: usize,
+    pub queries: SeriesQueries
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct SeriesQueries {
+    pub buckets: Vec<SeriesQueryBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct SeriesQueryBucket {
+    pub key: usize,
+    pub series: Series
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Series {
+    pub buckets: Vec<HourBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct HourBucket {
+    pub key: f64,
+    pub avg: OptionalValue
+}
+
+// Compute the hotcloud values (one per metric) for a single hour in-process: ES only
+// supplies the hourly averages of every (metric,query) series, the baseline, surprise
+// and percentile are all computed here.  Fails if the search does
+pub fn query_hour(es: &Arc<Cluster>, config: &Config, model: &Model, scorer: &Scorer, hour: usize) -> Result<Vec<HotcloudResult>, String> {
+    let response = match fetch_series(es, config, SearchParams::new(hour, config.detect.window)) {
+        Some(response) => response,
+        None => return Err("the search for the hourly series failed".to_owned())
+    };
+
+    let timestamp = (UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64)).format("%Y-%m-%dT%H:%M:%S").to_string();
+
+    Ok(response.aggregations.metrics.buckets.into_iter().filter_map(|metric| {
+        let surprises: Vec<(usize, f64)> = metric.queries.buckets.iter().filter_map(|query| {
+            let averages: Vec<Option<f64>> = query.series.buckets.iter().map(|b| b.avg.value).collect();
+            largest_surprise(model, scorer, config.detect.window, &averages).map(|s| (query.key, s))
+        }).collect();
+
+        let largest: Vec<f64> = surprises.iter().map(|&(_, s)| s).collect();
+        summarise(config, &largest).map(|(value, stats)| {
+            HotcloudResult {
+                metric: metric.key,
+                hour: timestamp.clone(),
+                value: value,
+                stats: stats,
+                top: attribution::top_queries(surprises, config.attribution.queries)
+            }
+        })
+    }).collect())
+}
+
+/// Fetch the hourly average of every (metric,query) series over a time range
+pub fn fetch_series(es: &Arc<Cluster>, config: &Config, params: SearchParams) -> Option<SeriesResponse> {
+    let search = Search {
+        filters: vec![params.filter()],
+        aggs: vec![("metrics", Agg::Terms { field: "metric", size: config.metrics, aggs: vec![
+            ("queries", Agg::Terms { field: "query", size: config.queries, aggs: vec![
+                ("series", averages_agg())
+            ]})
+        ]})]
+    };
+
+    es.search(Doc::Data, &search, "").and_then(|body| json::decode(&body).ok())
+}
+
+/// The plain hourly averages of a series, for scoring in-process
+pub fn averages_agg() -> Agg {
+    Agg::Hourly { field: "hour", aggs: vec![("avg", Agg::Avg("value"))] }
+}
+
+/// The largest surprise in a series of hourly averages, mirroring the `moving_avg`,
+/// `bucket_script` and `max_bucket` chain of the search template.  Empty hours are
+/// skipped, like the template's default gap policy
+pub fn largest_surprise(model: &Model, scorer: &Scorer, window: usize, averages: &[Option<f64>]) -> Option<f64> {
+    let mut history: Vec<f64> = Vec::with_capacity(window);
+    let mut largest = None;
+
+    for avg in averages {
+        let avg = match *avg {
+            Some(avg) => avg,
+            None => continue
+        };
+
+        let surprise = model.predict(&history).and_then(|expected| scorer.score(avg, expected, &history));
+        if let Some(surprise) = surprise {
+            largest = match largest {
+                Some(l) if l >= surprise => Some(l),
+                _ => Some(surprise)
+            };
+        }
+
+        if history.len() == window {
+            history.remove(0);
+        }
+        history.push(avg);
+    }
+
+    largest
+}
+
+/// Every requested statistic of a metric's per-query surprises, along with the headline
+/// value (the first of `detect.percentiles`)
+pub fn summarise(config: &Config, largest: &[f64]) -> Option<(f64, BTreeMap<String, f64>)> {
+    let headline = match percentile(largest, config.detect.percentiles[0]) {
+        Some(headline) => headline,
+        None => return None
+    };
+
+    let mut stats = BTreeMap::new();
+    for percent in &config.detect.percentiles {
+        stats.insert(stat_name(*percent), percentile(largest, *percent).unwrap());
+    }
+
+    for stat in &config.detect.stats {
+        match &**stat {
+            "max" => stats.insert("max".to_owned(), largest.iter().fold(headline, |acc, &v| acc.max(v))),
+            _ => stats.insert("mean".to_owned(), largest.iter().fold(0f64, |acc, v| acc + v) / largest.len() as f64)
+        };
+    }
+
+    Some((headline, stats))
+}
+
+/// The name a percentile is stored under, e.g. 90.0 -> p90 and 99.9 -> p99_9 (ES
+/// doesn't allow dots in field names)
+pub fn stat_name(percent: f64) -> String {
+    format!("p{}", percent).replace(".", "_")
+}
+
+/// Nearest-rank percentile, the same way ES' `percentiles_bucket` picks it.  NaNs have
+/// no rank and are left out
+pub fn percentile(values: &[f64], percent: f64) -> Option<f64> {
+    let mut sorted: Vec<f64> = values.iter().cloned().filter(|v| !v.is_nan()).collect();
+    if sorted.len() == 0 {
+        return None;
+    }
+
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let index = ((percent / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    Some(sorted[index])
+}
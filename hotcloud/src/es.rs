@@ -0,0 +1,612 @@
+use model::Model;
+use hyper::Client;
+use hyper::client::Response;
+use hyper::header::ContentType;
+use hyper::status::StatusCode;
+use hyper;
+use rustc_serialize::Encodable;
+use rustc_serialize::json::{self, Json};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::atomic::AtomicUsize;
+use stats::Stats;
+
+/// The flavour of requests a cluster understands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    /// Elasticsearch 2.x: mapping types, `filtered` queries, `moving_avg` and Groovy
+    /// scripts, templates stored under `/_search/template`
+    Legacy,
+    /// Elasticsearch 7.x: typeless indices, `bool` filters, `moving_fn` and Painless,
+    /// templates stored as mustache scripts
+    V7,
+    /// Elasticsearch 8.x: as 7.x, with `fixed_interval` date histograms
+    V8,
+    /// OpenSearch, which forked from 7.10 and takes the same requests as 8.x here
+    OpenSearch
+}
+
+impl Dialect {
+    /// The dialect of a cluster reporting version `number` and (for OpenSearch) `distribution`
+    pub fn from_version(number: &str, distribution: Option<&str>) -> Result<Dialect, String> {
+        if distribution == Some("opensearch") {
+            return Ok(Dialect::OpenSearch);
+        }
+
+        let major: usize = number.split('.').next().and_then(|m| m.parse().ok()).unwrap_or(0);
+        match major {
+            1 => Err(format!("Elasticsearch {} has no pipeline aggregations, the hotcloud needs 2.x or later", number)),
+            2 => Ok(Dialect::Legacy),
+            7 => Ok(Dialect::V7),
+            m if m >= 8 => Ok(Dialect::V8),
+            _ => Err(format!("Unsupported Elasticsearch version [{}], expected 2.x, 7.x, 8.x or OpenSearch", number))
+        }
+    }
+}
+
+/// The kinds of document hotcloud stores
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Doc {
+    Data,
+    Hotcloud,
+    Node,
+    ChangePoint
+}
+
+const DOCS: [Doc; 4] = [Doc::Data, Doc::Hotcloud, Doc::Node, Doc::ChangePoint];
+
+/// A document indexed in bulk.  Its id is derived from what it describes, so sending it
+/// again (when a run resumes from a checkpoint) replaces it instead of adding a duplicate
+pub trait Document: Encodable {
+    fn id(&self) -> String;
+
+    /// Write the JSON source of the document, exactly as `json::encode` would.  Documents
+    /// sent by the million override this to skip the intermediate `String`
+    fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> where Self: Sized {
+        out.write_all(json::encode(self).unwrap().as_bytes())
+    }
+
+    /// Write the id of the document, exactly as `id()` returns it
+    fn write_id<W: Write>(&self, out: &mut W) -> io::Result<()> where Self: Sized {
+        out.write_all(self.id().as_bytes())
+    }
+}
+
+/// Write the body of a `_bulk` request indexing `docs`: an action line with the id of each
+/// document, followed by its source
+pub fn write_bulk<T: Document, W: Write>(out: &mut W, docs: &[T]) -> io::Result<()> {
+    for doc in docs {
+        try!(write_bulk_doc(out, doc));
+    }
+    Ok(())
+}
+
+/// Write the action line and source of a single document of a `_bulk` body, for bodies
+/// written a document at a time
+pub fn write_bulk_doc<T: Document, W: Write>(out: &mut W, doc: &T) -> io::Result<()> {
+    try!(out.write_all(b"{\"index\":{\"_id\":\""));
+    try!(doc.write_id(out));
+    try!(out.write_all(b"\"}}\n"));
+    try!(doc.write_json(out));
+    out.write_all(b"\n")
+}
+
+/// Write `value` as a JSON number the way `json::encode` formats it: every digit, `.0`
+/// when there is no fractional part, and `null` if it isn't finite
+pub fn write_number<W: Write>(out: &mut W, value: f64) -> io::Result<()> {
+    if !value.is_finite() {
+        return out.write_all(b"null");
+    }
+
+    // Formatted on the stack.  Only huge values, hundreds of digits long, don't fit
+    let mut digits = [0u8; 64];
+    let len = {
+        let mut cursor = io::Cursor::new(&mut digits[..]);
+        match write!(cursor, "{}", value) {
+            Ok(()) => cursor.position() as usize,
+            Err(_) => return out.write_all(json::encode(&value).unwrap().as_bytes())
+        }
+    };
+    try!(out.write_all(&digits[..len]));
+    match digits[..len].contains(&b'.') {
+        true => Ok(()),
+        false => out.write_all(b".0")
+    }
+}
+
+#[derive(RustcDecodable, Debug)]
+struct Root {
+    version: Version
+}
+
+#[derive(RustcDecodable, Debug)]
+struct Version {
+    number: String,
+    distribution: Option<String>
+}
+
+/// A connection to the cluster, which knows where each kind of document lives and which
+/// dialect to build requests in
+pub struct Cluster {
+    pub client: Client,
+    pub url: String,
+    pub dialect: Dialect,
+    /// Bulks being sent right now, which the bulk senders throttle themselves against
+    pub in_flight: AtomicUsize,
+    /// Bulks of data handed to the background senders and not being sent yet
+    pub queued: AtomicUsize,
+    /// Timings of the bulks and queries sent to the cluster
+    pub stats: Stats
+}
+
+impl Cluster {
+
+    /// Connect to the cluster at `url` and detect its version
+    pub fn connect(url: &str) -> Result<Cluster, String> {
+        let url = url.trim_right_matches('/').to_owned();
+        let client = Client::new();
+
+        let body = match read(client.get(&url).send()) {
+            Some(body) => body,
+            None => return Err(format!("Could not reach a cluster at {}", url))
+        };
+        let root: Root = try!(json::decode(&body).map_err(|err| format!("Unexpected response from {}: {}", url, err)));
+        let dialect = try!(Dialect::from_version(&root.version.number, root.version.distribution.as_ref().map(|d| &**d)));
+
+        info!("Connected to {} {} at {}, using the {:?} dialect",
+              root.version.distribution.unwrap_or("elasticsearch".to_owned()), root.version.number, url, dialect);
+        Ok(Cluster {
+            client: client,
+            url: url,
+            dialect: dialect,
+            in_flight: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            stats: Stats::new()
+        })
+    }
+
+    /// The index documents of a kind are stored in.  Modern clusters only allow one
+    /// mapping per index, so node and change-point results get indices of their own
+    pub fn index(&self, doc: Doc) -> &'static str {
+        match (self.dialect, doc) {
+            (_, Doc::Data) => "data",
+            (_, Doc::Hotcloud) | (Dialect::Legacy, _) => "hotcloud",
+            (_, Doc::Node) => "hotcloud_node",
+            (_, Doc::ChangePoint) => "hotcloud_changepoint"
+        }
+    }
+
+    /// Every index, in creation order
+    pub fn indices(&self) -> Vec<&'static str> {
+        let mut indices = vec![];
+        for doc in DOCS.iter() {
+            let index = self.index(*doc);
+            if !indices.contains(&index) {
+                indices.push(index);
+            }
+        }
+        indices
+    }
+
+    /// The URL of an endpoint scoped to the documents of a kind (`_bulk`, `_search`...)
+    pub fn doc_url(&self, doc: Doc, endpoint: &str) -> String {
+        match self.dialect {
+            Dialect::Legacy => format!("{}/{}/{}/{}", self.url, self.index(doc), doc_type(doc), endpoint),
+            _ => format!("{}/{}/{}", self.url, self.index(doc), endpoint)
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<String> {
+        read(self.client.get(&format!("{}/{}", self.url, path)).send())
+    }
+
+    pub fn post(&self, path: &str, body: &str) -> Option<String> {
+        read(self.client.post(&format!("{}/{}", self.url, path)).header(ContentType::json()).body(body).send())
+    }
+
+    pub fn put(&self, path: &str, body: &str) -> Option<String> {
+        read(self.client.put(&format!("{}/{}", self.url, path)).header(ContentType::json()).body(body).send())
+    }
+
+    pub fn delete(&self, path: &str) -> Option<String> {
+        read(self.client.delete(&format!("{}/{}", self.url, path)).send())
+    }
+
+    /// Refresh the indices holding the given kinds of document
+    pub fn refresh(&self, docs: &[Doc]) {
+        let mut indices: Vec<&str> = docs.iter().map(|doc| self.index(*doc)).collect();
+        indices.dedup();
+        let _ = self.post(&format!("{}/_refresh", indices.join(",")), "");
+    }
+
+    /// The body that creates `index`, with the mappings of every kind of document in it
+    pub fn index_body(&self, index: &str) -> Json {
+        let docs: Vec<Doc> = DOCS.iter().cloned().filter(|doc| self.index(*doc) == index).collect();
+
+        let mappings = match self.dialect {
+            Dialect::Legacy => object(docs.iter().map(|doc| (doc_type(*doc), mapping(*doc).to_json(self.dialect))).collect()),
+            _ => mapping(docs[0]).to_json(self.dialect)
+        };
+
+        object(vec![
+            ("settings", self.settings(docs[0])),
+            ("mappings", mappings)
+        ])
+    }
+
+    // The raw data is only ever bulk indexed and aggregated, so it gets tuned further
+    fn settings(&self, doc: Doc) -> Json {
+        let mut settings = vec![
+            ("refresh_interval", Json::String("30s".to_owned())),
+            ("number_of_shards", Json::U64(1)),
+            ("number_of_replicas", Json::U64(0)),
+            ("index.translog.flush_threshold_size", Json::String("1gb".to_owned()))
+        ];
+
+        if doc == Doc::Data {
+            settings.push(("index.merge.scheduler.max_thread_count", Json::U64(1)));
+            if self.dialect == Dialect::Legacy {
+                settings.push(("index.store.throttle.type", Json::String("none".to_owned())));
+            }
+        }
+        object(settings)
+    }
+
+    /// Store `source` as the search template `id`, replacing any previous version
+    pub fn put_template(&self, id: &str, source: Json) {
+        match self.dialect {
+            Dialect::Legacy => {
+                let path = format!("_search/template/{}", id);
+                self.delete(&path);
+                self.post(&path, &object(vec![("template", source)]).to_string());
+            },
+            _ => {
+                let path = format!("_scripts/{}", id);
+                self.delete(&path);
+                let script = object(vec![
+                    ("lang", Json::String("mustache".to_owned())),
+                    ("source", source)
+                ]);
+                self.post(&path, &object(vec![("script", script)]).to_string());
+            }
+        }
+    }
+
+    /// Run a search over the documents of a kind, optionally trimming the response down
+    /// with `filter_path`
+    pub fn search(&self, doc: Doc, search: &Search, filter_path: &str) -> Option<String> {
+        let path = with_filter_path(self.doc_url(doc, "_search"), filter_path);
+        read(self.client.post(&path).header(ContentType::json()).body(&search.to_json(self.dialect).to_string()).send())
+    }
+
+    /// Run the stored search template `id` with `params`
+    pub fn search_template(&self, doc: Doc, id: &str, params: Vec<(&str, Json)>, filter_path: &str) -> Option<String> {
+        let id = Json::String(id.to_owned());
+        let body = match self.dialect {
+            Dialect::Legacy => object(vec![("template", object(vec![("id", id)])), ("params", object(params))]),
+            _ => object(vec![("id", id), ("params", object(params))])
+        };
+
+        let path = with_filter_path(self.doc_url(doc, "_search/template"), filter_path);
+        read(self.client.post(&path).header(ContentType::json()).body(&body.to_string()).send())
+    }
+}
+
+fn doc_type(doc: Doc) -> &'static str {
+    match doc {
+        Doc::Data | Doc::Hotcloud => "data",
+        Doc::Node => "node",
+        Doc::ChangePoint => "changepoint"
+    }
+}
+
+fn with_filter_path(url: String, filter_path: &str) -> String {
+    match filter_path.len() {
+        0 => url,
+        _ => format!("{}?filter_path={}", url, filter_path)
+    }
+}
+
+// The body of a successful response
+fn read(response: hyper::Result<Response>) -> Option<String> {
+    let mut response = match response {
+        Ok(r) => r,
+        Err(_) => return None
+    };
+
+    let mut body = String::new();
+    match response.status {
+        StatusCode::Ok | StatusCode::Created => {
+            let _ = response.read_to_string(&mut body);
+            Some(body)
+        },
+        _ => None
+    }
+}
+
+/// Build a JSON object from its fields
+pub fn object(fields: Vec<(&str, Json)>) -> Json {
+    let mut obj = BTreeMap::new();
+    for (key, value) in fields {
+        obj.insert(key.to_owned(), value);
+    }
+    Json::Object(obj)
+}
+
+/// The type of a mapped field
+pub enum Field {
+    Short,
+    Long,
+    Float,
+    Date,
+    Keyword,
+    Object(Vec<(&'static str, Field)>)
+}
+
+impl Field {
+    fn to_json(&self, dialect: Dialect) -> Json {
+        let name = match *self {
+            Field::Short => "short",
+            Field::Long => "long",
+            Field::Float => "float",
+            Field::Date => "date",
+            Field::Keyword => match dialect {
+                Dialect::Legacy => return object(vec![
+                    ("type", Json::String("string".to_owned())),
+                    ("index", Json::String("not_analyzed".to_owned()))
+                ]),
+                _ => "keyword"
+            },
+            Field::Object(ref fields) => return object(vec![("properties", properties(fields, dialect))])
+        };
+
+        // Doc values are on by default from 2.x, the oldest version supported
+        object(vec![("type", Json::String(name.to_owned()))])
+    }
+}
+
+fn properties(fields: &[(&'static str, Field)], dialect: Dialect) -> Json {
+    object(fields.iter().map(|&(name, ref field)| (name, field.to_json(dialect))).collect())
+}
+
+/// The mapping of one kind of document
+pub struct Mapping {
+    pub source: bool,
+    pub fields: Vec<(&'static str, Field)>,
+    /// Objects whose fields aren't known up front, but are all floats (e.g. `stats`)
+    pub float_objects: Vec<&'static str>
+}
+
+impl Mapping {
+    pub fn to_json(&self, dialect: Dialect) -> Json {
+        let mut mapping = vec![("properties", properties(&self.fields, dialect))];
+
+        if dialect == Dialect::Legacy {
+            mapping.push(("_all", object(vec![("enabled", Json::Boolean(false))])));
+        }
+        if !self.source {
+            mapping.push(("_source", object(vec![("enabled", Json::Boolean(false))])));
+        }
+        if self.float_objects.len() > 0 {
+            let templates = self.float_objects.iter().map(|name| {
+                object(vec![(name, object(vec![
+                    ("path_match", Json::String(format!("{}.*", name))),
+                    ("mapping", Field::Float.to_json(dialect))
+                ]))])
+            }).collect();
+            mapping.push(("dynamic_templates", Json::Array(templates)));
+        }
+
+        object(mapping)
+    }
+}
+
+/// The mapping of each kind of document
+pub fn mapping(doc: Doc) -> Mapping {
+    match doc {
+        Doc::Data => Mapping {
+            source: false,
+            fields: vec![
+                ("node", Field::Short),
+                ("query", Field::Short),
+                ("metric", Field::Short),
+                ("hour", Field::Date),
+                ("value", Field::Float),
+                ("disruption", Field::Short),
+                ("hit", Field::Short)
+            ],
+            float_objects: vec![]
+        },
+        Doc::Hotcloud => Mapping {
+            source: true,
+            fields: vec![
+                ("metric", Field::Long),
+                ("hour", Field::Date),
+                ("value", Field::Float),
+                ("top", Field::Object(vec![
+                    ("query", Field::Long),
+                    ("surprise", Field::Float),
+                    ("nodes", Field::Object(vec![
+                        ("node", Field::Long),
+                        ("surprise", Field::Float)
+                    ]))
+                ]))
+            ],
+            float_objects: vec!["stats"]
+        },
+        Doc::Node => Mapping {
+            source: true,
+            fields: vec![
+                ("metric", Field::Long),
+                ("node", Field::Long),
+                ("hour", Field::Date),
+                ("value", Field::Float),
+                ("query_value", Field::Float)
+            ],
+            float_objects: vec![]
+        },
+        Doc::ChangePoint => Mapping {
+            source: true,
+            fields: vec![
+                ("metric", Field::Long),
+                ("query", Field::Long),
+                ("hour", Field::Date),
+                ("detected", Field::Date),
+                ("method", Field::Keyword),
+                ("magnitude", Field::Float)
+            ],
+            float_objects: vec![]
+        }
+    }
+}
+
+/// A script in each of the languages clusters offer
+#[derive(Debug, Clone)]
+pub struct Script {
+    /// Groovy, for the legacy dialect
+    pub groovy: &'static str,
+    /// Painless, for everything else
+    pub painless: &'static str
+}
+
+/// A filter on the documents being searched
+pub enum Filter {
+    /// `gte <= field <= lte`, as dates or mustache placeholders
+    Range { field: &'static str, gte: String, lte: String },
+    Terms { field: &'static str, values: Vec<usize> }
+}
+
+impl Filter {
+    fn to_json(&self) -> Json {
+        match *self {
+            Filter::Range { field, ref gte, ref lte } => object(vec![("range", object(vec![(field, object(vec![
+                ("gte", Json::String(gte.clone())),
+                ("lte", Json::String(lte.clone()))
+            ]))]))]),
+            Filter::Terms { field, ref values } => object(vec![("terms", object(vec![
+                (field, Json::Array(values.iter().map(|v| Json::U64(*v as u64)).collect()))
+            ]))])
+        }
+    }
+}
+
+/// Named aggregations, in order
+pub type Aggs = Vec<(&'static str, Agg)>;
+
+/// An aggregation, and its sub-aggregations for the bucketing ones
+pub enum Agg {
+    Terms { field: &'static str, size: usize, aggs: Aggs },
+    /// One bucket per hour of a date field
+    Hourly { field: &'static str, aggs: Aggs },
+    Avg(&'static str),
+    Min(&'static str),
+    Max(&'static str),
+    /// The value of `path` predicted by `model` from the previous `window` buckets
+    MovingAvg { path: &'static str, window: usize, model: Model },
+    /// A script over the named `paths` of each bucket
+    BucketScript { paths: Vec<(&'static str, &'static str)>, script: Script },
+    MaxBucket(&'static str),
+    AvgBucket(&'static str),
+    PercentilesBucket { path: &'static str, percents: Vec<f64> }
+}
+
+impl Agg {
+    fn to_json(&self, dialect: Dialect) -> Json {
+        let path = |path: &str| Json::String(path.to_owned());
+        let field = |field: &str| object(vec![("field", Json::String(field.to_owned()))]);
+
+        let (kind, body, aggs) = match *self {
+            Agg::Terms { field, size, ref aggs } => ("terms", object(vec![
+                ("field", Json::String(field.to_owned())),
+                ("size", Json::U64(size as u64))
+            ]), Some(aggs)),
+            Agg::Hourly { field, ref aggs } => {
+                let interval = match dialect {
+                    Dialect::Legacy => ("interval", Json::String("hour".to_owned())),
+                    Dialect::V7 => ("interval", Json::String("1h".to_owned())),
+                    Dialect::V8 | Dialect::OpenSearch => ("fixed_interval", Json::String("1h".to_owned()))
+                };
+                ("date_histogram", object(vec![("field", Json::String(field.to_owned())), interval]), Some(aggs))
+            },
+            Agg::Avg(name) => ("avg", field(name), None),
+            Agg::Min(name) => ("min", field(name), None),
+            Agg::Max(name) => ("max", field(name), None),
+            // `moving_avg` is gone in 8.x, `moving_fn` (6.4+) runs the same models as scripts
+            Agg::MovingAvg { path: buckets_path, window, ref model } => match dialect {
+                Dialect::Legacy => {
+                    let mut moving_avg = vec![
+                        ("buckets_path", path(buckets_path)),
+                        ("window", Json::U64(window as u64)),
+                        ("model", Json::String(model.name().to_owned()))
+                    ];
+                    if let Some(settings) = model.settings() {
+                        moving_avg.push(("settings", settings));
+                    }
+                    ("moving_avg", object(moving_avg), None)
+                },
+                _ => ("moving_fn", object(vec![
+                    ("buckets_path", path(buckets_path)),
+                    ("window", Json::U64(window as u64)),
+                    ("script", Json::String(model.moving_fn()))
+                ]), None)
+            },
+            Agg::BucketScript { ref paths, ref script } => {
+                let source = match dialect {
+                    Dialect::Legacy => script.groovy,
+                    _ => script.painless
+                };
+                ("bucket_script", object(vec![
+                    ("buckets_path", object(paths.iter().map(|&(name, p)| (name, path(p))).collect())),
+                    ("script", Json::String(source.to_owned()))
+                ]), None)
+            },
+            Agg::MaxBucket(buckets_path) => ("max_bucket", object(vec![("buckets_path", path(buckets_path))]), None),
+            Agg::AvgBucket(buckets_path) => ("avg_bucket", object(vec![("buckets_path", path(buckets_path))]), None),
+            Agg::PercentilesBucket { path: buckets_path, ref percents } => ("percentiles_bucket", object(vec![
+                ("buckets_path", path(buckets_path)),
+                ("percents", Json::Array(percents.iter().map(|p| Json::F64(*p)).collect()))
+            ]), None)
+        };
+
+        match aggs {
+            Some(aggs) if aggs.len() > 0 => object(vec![(kind, body), ("aggs", aggs_json(aggs, dialect))]),
+            _ => object(vec![(kind, body)])
+        }
+    }
+}
+
+fn aggs_json(aggs: &Aggs, dialect: Dialect) -> Json {
+    object(aggs.iter().map(|&(name, ref agg)| (name, agg.to_json(dialect))).collect())
+}
+
+/// An aggregation-only search
+pub struct Search {
+    pub filters: Vec<Filter>,
+    pub aggs: Aggs
+}
+
+impl Search {
+    pub fn to_json(&self, dialect: Dialect) -> Json {
+        let mut search = vec![
+            ("size", Json::U64(0)),
+            ("aggs", aggs_json(&self.aggs, dialect))
+        ];
+
+        if self.filters.len() > 0 {
+            let filters: Vec<Json> = self.filters.iter().map(|f| f.to_json()).collect();
+            let query = match dialect {
+                Dialect::Legacy => {
+                    let filter = match filters.len() {
+                        1 => filters.into_iter().next().unwrap(),
+                        _ => object(vec![("bool", object(vec![("must", Json::Array(filters))]))])
+                    };
+                    object(vec![("filtered", object(vec![("filter", filter)]))])
+                },
+                _ => object(vec![("bool", object(vec![("filter", Json::Array(filters))]))])
+            };
+            search.push(("query", query));
+        }
+
+        object(search)
+    }
+}
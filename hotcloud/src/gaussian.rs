@@ -0,0 +1,28 @@
+use rand::Rng;
+use std::f64::consts::PI;
+
+/// Fill `out` with standard normal samples using the Box-Muller transform over the whole
+/// buffer: the uniforms are drawn first, then turned into gaussians a pair at a time in
+/// a tight loop with no branches on the data, which the compiler can unroll
+pub fn fill<R: Rng>(rng: &mut R, out: &mut [f64]) {
+    for x in out.iter_mut() {
+        *x = rng.gen::<f64>();
+    }
+
+    // An odd-sized buffer draws one more uniform for the angle of its last sample
+    let extra = match out.len() % 2 {
+        1 => rng.gen::<f64>(),
+        _ => 0.0
+    };
+
+    for pair in out.chunks_mut(2) {
+        // 1 - u is in (0, 1], so the logarithm is finite
+        let radius = (-2.0 * (1.0 - pair[0]).ln()).sqrt();
+        let angle = 2.0 * PI * if pair.len() == 2 { pair[1] } else { extra };
+        let (sin, cos) = angle.sin_cos();
+        pair[0] = radius * cos;
+        if pair.len() == 2 {
+            pair[1] = radius * sin;
+        }
+    }
+}
@@ -1,238 +1,837 @@
 
 use config::Config;
-use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::mpsc::channel;
 use std::thread;
-use rand::distributions::{LogNormal, Normal, IndependentSample};
-use rand::{thread_rng, ThreadRng, Rng};
+use rand::distributions::LogNormal;
+use rand::{self, Rng, SeedableRng, XorShiftRng};
 use std::collections::HashMap;
-use hyper::Client;
+use checkpoint::Checkpoint;
+use shutdown::Shutdown;
+use es::{self, Cluster, Doc, Document};
 use rustc_serialize::{Encodable, Encoder};
-use rustc_serialize::json::{self};
 use threadpool::ThreadPool;
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use chrono::{Duration, UTC};
 use chrono::offset::TimeZone;
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::vec;
 use ::Disruption;
+use dashboard::{Event, Reporter};
+use gaussian;
+use batch::BatchSize;
+
+/// The generated data-point for a particular (node,metric,query) tuple.
+/// `value` contains the generated gaussian, `disruption` represents if/what
+/// disruption was active (0 for none, then 1, 2 and 3 for node, query and metric), and
+/// `hit` is the same but only on the tuples the disruption changed, the ground truth
+/// per tuple
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct TupleResult {
+    pub node: usize,
+    pub metric: usize,
+    pub query: usize,
+    /// The timestamp of the hour, shared by every document of it rather than copied
+    pub hour: Arc<String>,
+    pub value: f64,
+    pub disruption: usize,
+    pub hit: usize
+}
+
+impl Document for TupleResult {
+    fn id(&self) -> String {
+        format!("{}_{}_{}_{}", self.hour, self.node, self.query, self.metric)
+    }
 
-// The generated data-point for a particular (node,metric,query) tuple.
-// value contains the generated gaussian, disruption represents if/what
-// disruption was active
-#[derive(RustcDecodable, RustcEncodable)]
-struct TupleResult {
-    node: usize,
-    metric: usize,
-    query: usize,
-    hour: String,
-    value: f64,
-    disruption: usize
+    // The bulk of what a run indexes, so written field by field straight into the bulk
+    // body, in the order `json::encode` writes the fields in.  The hour is a timestamp,
+    // with nothing to escape
+    fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(write!(out, "{{\"node\":{},\"metric\":{},\"query\":{},\"hour\":\"{}\",\"value\":",
+                    self.node, self.metric, self.query, self.hour));
+        try!(es::write_number(out, self.value));
+        write!(out, ",\"disruption\":{},\"hit\":{}}}", self.disruption, self.hit)
+    }
+
+    fn write_id<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "{}_{}_{}_{}", self.hour, self.node, self.query, self.metric)
+    }
 }
 
+/// A disruption of the timeline: when it starts, how many hours it lasts and what it hits
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct Scheduled {
+    pub start: usize,
+    pub length: usize,
+    pub disruption: Disruption
+}
+
+// The random streams of a run: one for the distributions, one for the disruptions, and
+// one for the gaussians of every (hour, node) from HOURS onwards
+const DISTRIBUTIONS: u64 = 0;
+const SCHEDULE: u64 = 1;
+const HOURS: u64 = 2;
+
 // Gaussian distribution
 struct NormalParams {
     mean: usize,
     std: usize
 }
 
-// Run the simulation, generating a "cluster history" and simulated disruptions
-pub fn generate_timeline(client: &Arc<Client>, config: &Config, json: bool) {
+/// Where generated documents go, a bulk at a time
+pub trait Sink: Send {
+    /// Take a bulk of documents
+    fn send(&mut self, bulk: Vec<TupleResult>);
 
-    // We generate gaussians on another thread and cache them in a channel
-    // to be grabbed when necessary, since gaussian generation is a bit slow
-    let rx = start_normal_generator();
+    /// Take a bulk of documents along with its `_bulk` body, already written to cut the
+    /// bulk at `es.bulk_bytes`.  Sinks that send bulk bodies use it rather than writing
+    /// the documents again
+    fn send_body(&mut self, bulk: Vec<TupleResult>, _body: Vec<u8>) {
+        self.send(bulk)
+    }
 
-    // Threadpool for the client bulks
-    let pool = ThreadPool::new(config.threads);
-    let mut bulk: Vec<TupleResult> = Vec::with_capacity(config.es.bulk_size);
+    /// The timeline is complete: return once everything sent has landed, or fail if some
+    /// of it never will
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
 
-    let mut rng = thread_rng();
+    /// The most documents and bytes (0 for no limit) the next bulk should hold, if the
+    /// sink wants it smaller than the configured `es.bulk_size` and `es.bulk_bytes`
+    fn bulk_size(&self) -> Option<(usize, usize)> {
+        None
+    }
+}
 
-    // Generate a list of disruptions that will be seeded into our timeline
-    let disruptions = generate_disruptions(&config, &mut rng);
+/// Indexes documents into the `data` index, `threads` bulks at a time in the background
+pub struct EsSink {
+    es: Arc<Cluster>,
+    reporter: Reporter,
+    pool: ThreadPool,
+    threads: usize,
+    pending: Arc<AtomicUsize>,
+    // Bulks given up on so far
+    failed: Arc<AtomicUsize>,
+    batch: Arc<BatchSize>
+}
 
-    // Generate the "regular" and "disrupted" distributions for
-    // every (node,metric,query) tupe
-    let distributions = generate_distributions(&config, &mut rng);
+impl EsSink {
+    pub fn new(es: &Arc<Cluster>, config: &Config, reporter: &Reporter) -> EsSink {
+        EsSink {
+            es: es.clone(),
+            reporter: reporter.clone(),
+            pool: ThreadPool::new(config.threads),
+            threads: config.threads,
+            pending: Arc::new(AtomicUsize::new(0)),
+            failed: Arc::new(AtomicUsize::new(0)),
+            batch: Arc::new(BatchSize::new(config))
+        }
+    }
 
-    // Generate the timeline
-    debug!("Generating timeline...");
-    let (mut disruption, mut counter) = (None, 0);
-    for hour in 0..config.hours {
+    fn wait_below(&self, pending: usize) {
+        while self.pending.load(Ordering::SeqCst) > pending {
+            thread::sleep_ms(50);
+        }
+    }
 
-        debug!("{} -- {}", hour, bulk.len());
+    // The body of the bulk is written on the sending thread, unless it comes with one
+    fn dispatch(&mut self, bulk: Vec<TupleResult>, body: Option<Vec<u8>>) {
+        // Wait for a free thread and fire off the bulk in the background
+        let threads = self.threads;
+        self.wait_below(threads - 1);
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.es.queued.fetch_add(1, Ordering::SeqCst);
+
+        let size = bulk.len();
+        let (es, reporter, pending, batch) = (self.es.clone(), self.reporter.clone(), self.pending.clone(), self.batch.clone());
+        let failed = self.failed.clone();
+        self.pool.execute(move|| {
+            es.queued.fetch_sub(1, Ordering::SeqCst);
+            let sent = match body {
+                Some(body) => ::util::send_body(&es, Doc::Data, size, &body),
+                None => ::util::send_bulk(&es, Doc::Data, bulk)
+            };
+            if sent.millis.is_none() {
+                failed.fetch_add(1, Ordering::SeqCst);
+            }
+            batch.observe(&sent);
+            reporter.send(Event::Indexed(size));
+            pending.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
 
-        // if we are not currently in a disruption, check to see
-        // if there is one this hour
-        if counter <= 0 {
-            disruption = disruptions.get(&hour);
-            counter = disruption.map_or(0, |&(_, x)| x);
+impl Sink for EsSink {
+    fn send(&mut self, bulk: Vec<TupleResult>) {
+        self.dispatch(bulk, None);
+    }
+
+    fn send_body(&mut self, bulk: Vec<TupleResult>, body: Vec<u8>) {
+        self.dispatch(bulk, Some(body));
+    }
+
+    // Once a bulk has been given up on, every later finish fails too: the hours after it
+    // landing doesn't fill the hole
+    fn finish(&mut self) -> Result<(), String> {
+        self.wait_below(0);
+        match self.failed.load(Ordering::SeqCst) {
+            0 => Ok(()),
+            failed => Err(format!("{} bulks could not be indexed into {}", failed, self.es.index(Doc::Data)))
         }
+    }
 
-        if disruption.is_some() {
-            print!(".");
+    fn bulk_size(&self) -> Option<(usize, usize)> {
+        Some(self.batch.limits())
+    }
+}
+
+/// Appends documents to a file as newline-delimited JSON
+pub struct JsonSink {
+    file: BufWriter<File>
+}
+
+impl JsonSink {
+    pub fn create(path: &str) -> io::Result<JsonSink> {
+        let file = try!(OpenOptions::new().write(true).create(true).append(true).open(path));
+        Ok(JsonSink {
+            file: BufWriter::with_capacity(1 << 20, file)
+        })
+    }
+}
+
+impl Sink for JsonSink {
+    fn send(&mut self, bulk: Vec<TupleResult>) {
+        for b in bulk {
+            let _ = b.write_json(&mut self.file).and_then(|_| self.file.write_all(b"\n"));
         }
+    }
 
-        // iterate through all the (node,query,metric) tuples
-        for node in 0..config.nodes {
-            for query in 0..config.queries {
-                for metric in 0..config.metrics {
-
-                    // Set the disruption flag for this tuple at this hour, based on the
-                    // disruption type
-                    let d = distributions.get(&(node, query, metric)).unwrap();
-                    let is_disrupted = counter > 0 && match disruption {
-                        None => false,
-                        Some(&(Disruption::Query(ref v), _)) => v.contains(&query),
-                        Some(&(Disruption::Metric(ref v), _)) => v.contains(&metric),
-                        Some(&(Disruption::Node(ref v), _)) => *v == node
-                    };
-
-                    // Grab a gaussian from the normalGenerator thread and use the "regular"
-                    // or "disrupted" distributions to find the final value
-                    let value = match is_disrupted {
-                        true => (rx.recv().unwrap() * d.1.std as f64) + d.1.mean as f64,
-                        false => (rx.recv().unwrap() * d.0.std as f64) + d.0.mean as f64
-                    };
-
-                    let timestamp = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64);
-
-                    bulk.push(TupleResult {
-                        node: node,
-                        metric: metric,
-                        query: query,
-                        hour: timestamp.format("%Y-%m-%dT%H:%M:%S").to_string(),
-                        value: value,
-                        disruption: ::util::disruption_to_usize(&disruption)
-                    });
-                }
+    fn finish(&mut self) -> Result<(), String> {
+        self.file.flush().map_err(|err| err.to_string())
+    }
+}
+
+/// Simulates the history of a cluster: a timeline of disruptions, and the value of every
+/// (node, query, metric) tuple each hour.  Everything is drawn from a seed, random unless
+/// set, and any hour can be generated on its own exactly as it would be in a full run
+///
+/// ```no_run
+/// # use hotcloud::{Config, Generator, JsonSink};
+/// let config = Config::load("config.toml").unwrap();
+///
+/// // Ship the whole history to a sink
+/// let sink = JsonSink::create("output.json").unwrap();
+/// Generator::new(&config).run(Box::new(sink)).unwrap();
+///
+/// // Or look at it in memory
+/// for hour in Generator::new(&config).timeline() {
+///     println!("{}: {:?}, {} docs", hour.hour, hour.disruption, hour.docs.len());
+/// }
+/// ```
+pub struct Generator<'a> {
+    config: &'a Config,
+    reporter: Reporter,
+    shutdown: Shutdown,
+    seed: u64,
+    checkpoint: Option<&'a Checkpoint>
+}
+
+impl<'a> Generator<'a> {
+    pub fn new(config: &'a Config) -> Generator<'a> {
+        Generator {
+            config: config,
+            reporter: Reporter::none(),
+            shutdown: Shutdown::new(),
+            seed: rand::random(),
+            checkpoint: None
+        }
+    }
+
+    /// Report progress and disruptions to a dashboard
+    pub fn reporter(mut self, reporter: &Reporter) -> Generator<'a> {
+        self.reporter = reporter.clone();
+        self
+    }
+
+    /// Stop `run` and `stream` once `shutdown` is requested
+    pub fn shutdown(mut self, shutdown: &Shutdown) -> Generator<'a> {
+        self.shutdown = shutdown.clone();
+        self
+    }
+
+    /// Generate the same data every time
+    pub fn seed(mut self, seed: u64) -> Generator<'a> {
+        self.seed = seed;
+        self
+    }
+
+    /// Continue the run saved in `checkpoint`, with its seed and disruptions, and save
+    /// progress to it every `checkpoint.interval` hours
+    pub fn checkpoint(mut self, checkpoint: &'a Checkpoint) -> Generator<'a> {
+        self.seed = checkpoint.progress().seed;
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+
+    /// The `config.disruptions` disruptions of the timeline, in order
+    pub fn schedule(&self) -> Vec<Scheduled> {
+        let mut schedule: Vec<Scheduled> = generate_disruptions(self.config, &mut stream(self.seed, SCHEDULE))
+            .into_iter()
+            .map(|(start, (disruption, length))| Scheduled { start: start, length: length, disruption: disruption })
+            .collect();
+        schedule.sort_by(|a, b| a.start.cmp(&b.start));
+        schedule
+    }
+
+    /// The `config.hours` hours of history, with `config.disruptions` disruptions
+    /// scheduled up front
+    pub fn timeline(&self) -> Timeline<'a> {
+        self.timeline_from(0)
+    }
+
+    /// The history from hour `first` on, the same as the rest of `timeline()`
+    pub fn timeline_from(&self, first: usize) -> Timeline<'a> {
+        let schedule = match self.checkpoint {
+            Some(checkpoint) => checkpoint.progress().disruptions,
+            None => self.schedule()
+        };
+        let disruptions = schedule.into_iter().map(|s| (s.start, (s.disruption, s.length))).collect();
+        Timeline::new(self.config, self.seed, first, Some(self.config.hours), Schedule::Fixed(disruptions))
+    }
+
+    /// An endless timeline starting at `first`, with disruptions injected at the same
+    /// average rate as the batch timeline
+    pub fn live(&self, first: usize) -> Timeline<'a> {
+        let rate = self.config.disruptions as f64 / self.config.hours as f64;
+        Timeline::new(self.config, self.seed, first, None, Schedule::Random(rate))
+    }
+
+    /// Run the simulation, generating a "cluster history" with simulated disruptions,
+    /// and return once all of it has reached the sink.  With a checkpoint, the run
+    /// starts after the last hour saved to it.  A shutdown stops it after the current
+    /// hour, which is sent and saved first.  Fails, without saving anything past the
+    /// last hour that landed, if the sink couldn't take some of it
+    pub fn run(self, sink: Box<Sink>) -> Result<(), String> {
+        let mut bulker = Bulker::new(self.config, sink);
+        let first = self.checkpoint.map_or(0, |checkpoint| checkpoint.progress().generated);
+        let mut generated = first;
+        let mut landed = Ok(());
+
+        debug!("Generating timeline from hour {}...", first);
+        for hour in self.timeline_from(first) {
+            if self.shutdown.requested() {
+                debug!("Shutdown requested, stopping at hour {}", hour.hour);
+                break;
             }
 
-            if bulk.len() >= config.es.bulk_size {
-                // Option to dump to a JSON file instead of ES
-                if json {
-                    let mut file = OpenOptions::new()
-                        .read(false)
-                        .write(true)
-                        .create(true)
-                        .append(true)
-                        .open("output.json")
-                        .unwrap();
-
-                    for b in bulk {
-                        let _ = file.write(&json::encode(&b).unwrap().into_bytes());
-                        let _ = file.write(b"\n");
+            generated = hour.hour + 1;
+            self.report(&hour);
+            bulker.push(hour.docs);
+
+            // Only hours that have reached the sink are saved as generated.  Once some of
+            // it is lost nothing later can be saved, so there's no point going on
+            if let Some(checkpoint) = self.checkpoint {
+                if generated % self.config.checkpoint.interval == 0 {
+                    landed = bulker.sync();
+                    if landed.is_err() {
+                        break;
                     }
+                    checkpoint.update(|progress| progress.generated = generated);
+                }
+            }
+        }
 
-                    bulk = Vec::with_capacity(config.es.bulk_size);
+        let finished = bulker.finish();
+        try!(landed.and(finished));
+        if let Some(checkpoint) = self.checkpoint {
+            checkpoint.update(|progress| progress.generated = generated);
+        }
+        Ok(())
+    }
 
-                } else {
-                    // Otherwise wait for a free thread and fire off the bulk in the background
-                    while ::ACTIVE_THREADS.load(Ordering::SeqCst) >= config.threads {
-                        thread::sleep_ms(500);
-                    }
-                    debug!(".");
-                    let client_clone = client.clone();
-                    pool.execute(move|| {
-                        ::util::send_bulk("http://localhost:9200/data/data/_bulk", &client_clone, bulk);
-                    });
-                    bulk = Vec::with_capacity(config.es.bulk_size);
+    /// Run the simulation in (optionally accelerated) real time.  Each hour is emitted
+    /// once the simulated clock has passed its end, disruptions are injected as we go,
+    /// and the stream only stops when a shutdown is requested
+    pub fn stream(self, sink: Box<Sink>) {
+        let config = self.config;
+        let mut bulker = Bulker::new(config, sink);
+
+        // The simulated clock starts at "now", after a burst of backfilled history
+        // so the moving averages have something to work with
+        let epoch = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0);
+        let started = UTC::now();
+        let first = ((started - epoch).num_hours() as usize).saturating_sub(config.stream.backfill);
+
+        debug!("Streaming timeline from hour {} at {}x...", first, config.stream.speed);
+        let mut timeline = self.live(first);
+        for hour in first.. {
+
+            // Sleep until the simulated clock reaches the end of this hour
+            let end = epoch + Duration::hours(hour as i64 + 1);
+            let due = ((end - started).num_milliseconds() as f64 / config.stream.speed) as i64;
+            let elapsed = (UTC::now() - started).num_milliseconds();
+            if due > elapsed {
+                self.shutdown.sleep_ms((due - elapsed) as u32);
+            }
+            if self.shutdown.requested() {
+                debug!("Shutdown requested, stopping at hour {}", hour);
+                break;
+            }
+
+            let hour = timeline.next().unwrap();
+            self.report(&hour);
+            bulker.push(hour.docs);
+
+            // Ship every hour as soon as it is complete rather than waiting for a full bulk
+            bulker.flush();
+        }
+
+        if let Err(err) = bulker.finish() {
+            error!("{}", err);
+        }
+    }
+
+    fn report(&self, hour: &Hour) {
+        if let (true, Some(d)) = (hour.started, hour.disruption.as_ref()) {
+            self.reporter.disrupted(hour.hour, d);
+        }
+        self.reporter.send(Event::Generated(hour.hour));
+    }
+}
+
+/// One simulated hour: the disruption active during it, if any, and the value of every
+/// (node, query, metric) tuple
+#[derive(Debug)]
+pub struct Hour {
+    pub hour: usize,
+    /// The disruption and its length in hours
+    pub disruption: Option<(Disruption, usize)>,
+    /// Whether the disruption starts this hour
+    pub started: bool,
+    pub docs: Vec<TupleResult>
+}
+
+// How disruptions are picked as the timeline goes
+enum Schedule {
+    // Picked up front, by start hour
+    Fixed(HashMap<usize, (Disruption, usize)>),
+    // Injected at random, with this probability every hour no disruption is active
+    Random(f64)
+}
+
+/// The generated hours, one at a time and in order
+pub struct Timeline<'a> {
+    config: &'a Config,
+    rng: XorShiftRng,
+    seed: u64,
+    // Generates the nodes of an hour in parallel, with more than one thread
+    pool: Option<ThreadPool>,
+    // The "regular" and "disrupted" distributions of every (node,query,metric) tuple, in
+    // the order the gaussians are sampled
+    distributions: Arc<Vec<(NormalParams, NormalParams)>>,
+    schedule: Schedule,
+    hour: usize,
+    end: Option<usize>,
+    disruption: Option<(Disruption, usize)>,
+    // Hours left of the current disruption
+    counter: usize
+}
+
+impl<'a> Timeline<'a> {
+    fn new(config: &'a Config, seed: u64, first: usize, end: Option<usize>, schedule: Schedule) -> Timeline<'a> {
+        let distributions = generate_distributions(config, &mut stream(seed, DISTRIBUTIONS));
+        let mut timeline = Timeline {
+            config: config,
+            rng: stream(seed, SCHEDULE),
+            seed: seed,
+            pool: if config.threads > 1 { Some(ThreadPool::new(config.threads)) } else { None },
+            distributions: Arc::new(distributions),
+            schedule: schedule,
+            hour: first,
+            end: end,
+            disruption: None,
+            counter: 0
+        };
+
+        // Play the schedule up to the first hour, which may be in the middle of a disruption
+        if let Schedule::Fixed(_) = timeline.schedule {
+            for hour in 0..first {
+                timeline.start_hour(hour);
+                timeline.end_hour();
+            }
+        }
+        timeline
+    }
+
+    /// The tuples of every hour, one at a time
+    pub fn records(self) -> Records<'a> {
+        Records {
+            timeline: self,
+            docs: Vec::new().into_iter()
+        }
+    }
+
+    // If we are not currently in a disruption, check to see if there is one this hour.
+    // Returns whether one started
+    fn start_hour(&mut self, hour: usize) -> bool {
+        if self.counter > 0 {
+            return false;
+        }
+
+        self.disruption = match self.schedule {
+            Schedule::Fixed(ref disruptions) => disruptions.get(&hour).cloned(),
+            Schedule::Random(rate) if self.rng.gen::<f64>() < rate => {
+                let length = self.rng.gen_range(2, 24);
+                Some((generate_disruption(self.config, &mut self.rng, hour, length), length))
+            },
+            Schedule::Random(_) => None
+        };
+        self.counter = self.disruption.as_ref().map_or(0, |&(_, x)| x);
+        self.disruption.is_some()
+    }
+
+    // Decrease the disruption counter.  Disruptions are 2-24 hours long,
+    // when counter reaches zero the disruption is over
+    fn end_hour(&mut self) {
+        if self.counter > 0 {
+            self.counter -= 1;
+            if self.counter == 0 {
+                self.disruption = None;
+            }
+        }
+    }
+
+    // Generate the value of every (node,query,metric) tuple for a single hour.  Nodes are
+    // independent of each other, so with a pool each one is generated on a worker
+    fn generate_hour(&self, hour: usize) -> Vec<TupleResult> {
+        let config = self.config;
+        let timestamp = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64);
+        let shard = Shard {
+            seed: self.seed,
+            hour: hour,
+            timestamp: Arc::new(timestamp.format("%Y-%m-%dT%H:%M:%S").to_string()),
+            disruption: self.disruption.clone(),
+            distributions: self.distributions.clone(),
+            nodes: config.nodes,
+            queries: config.queries,
+            metrics: config.metrics
+        };
+
+        let mut docs = Vec::with_capacity(config.nodes * config.queries * config.metrics);
+        let pool = match self.pool {
+            Some(ref pool) if config.nodes > 1 => pool,
+            _ => {
+                for node in 0..config.nodes {
+                    docs.extend(shard.generate(node));
                 }
+                return docs;
             }
+        };
+
+        let shard = Arc::new(shard);
+        let (tx, rx) = channel();
+        for node in 0..config.nodes {
+            let (shard, tx) = (shard.clone(), tx.clone());
+            pool.execute(move || {
+                let _ = tx.send((node, shard.generate(node)));
+            });
         }
 
-        // Decrease the disruption counter.  Disruptions are 2-24 hours long,
-        // when counter reaches zero the disruption is over
-        if counter > 0 {
-            counter -= 1;
+        // Put the nodes back in order, whichever finished first.  Only the workers hold a
+        // sender now, so a worker that panicked ends the results early rather than
+        // leaving us waiting for them forever
+        drop(tx);
+        let mut nodes = vec![vec![]; config.nodes];
+        let mut received = 0;
+        for (node, generated) in rx.iter() {
+            nodes[node] = generated;
+            received += 1;
+        }
+        if received < config.nodes {
+            panic!("A worker died generating hour {}: only {} of its {} nodes were generated", hour, received, config.nodes);
+        }
+        for generated in nodes {
+            docs.extend(generated);
         }
+        docs
     }
+}
+
+// Everything needed to generate the tuples of one hour, a node at a time on any thread.
+// A node's values only depend on its own random stream, so they come out the same
+// however the nodes are split between threads
+struct Shard {
+    seed: u64,
+    hour: usize,
+    timestamp: Arc<String>,
+    disruption: Option<(Disruption, usize)>,
+    distributions: Arc<Vec<(NormalParams, NormalParams)>>,
+    nodes: usize,
+    queries: usize,
+    metrics: usize
+}
+
+impl Shard {
+    fn generate(&self, node: usize) -> Vec<TupleResult> {
+        let disruption = self.disruption.as_ref();
+        let flag = ::util::disruption_to_usize(&disruption);
+        let tuples = self.queries * self.metrics;
+        let gaussians = sample_node(self.seed, self.hour, node, self.nodes, tuples);
+        let mut tuples = gaussians.iter().zip(&self.distributions[node * tuples..(node + 1) * tuples]);
+
+        // iterate through all the (query,metric) tuples of the node
+        let mut docs = Vec::with_capacity(self.queries * self.metrics);
+        for query in 0..self.queries {
+            for metric in 0..self.metrics {
+                let (gaussian, d) = tuples.next().unwrap();
+
+                // Set the disruption flag for this tuple at this hour, based on the
+                // disruption type
+                let is_disrupted = match disruption {
+                    None => false,
+                    Some(&(Disruption::Query(ref v), _)) => v.contains(&query),
+                    Some(&(Disruption::Metric(ref v), _)) => v.contains(&metric),
+                    Some(&(Disruption::Node(ref v), _)) => *v == node
+                };
+
+                // Scale the tuple's gaussian with its "regular" or "disrupted"
+                // distribution to find the final value
+                let value = match is_disrupted {
+                    true => (gaussian * d.1.std as f64) + d.1.mean as f64,
+                    false => (gaussian * d.0.std as f64) + d.0.mean as f64
+                };
+
+                docs.push(TupleResult {
+                    node: node,
+                    metric: metric,
+                    query: query,
+                    hour: self.timestamp.clone(),
+                    value: value,
+                    disruption: flag,
+                    hit: if is_disrupted { flag } else { 0 }
+                });
+            }
+        }
+
+        docs
+    }
+}
+
+impl<'a> Iterator for Timeline<'a> {
+    type Item = Hour;
+
+    fn next(&mut self) -> Option<Hour> {
+        let hour = self.hour;
+        if self.end.map_or(false, |end| hour >= end) {
+            return None;
+        }
+        self.hour += 1;
+
+        let started = self.start_hour(hour);
+        let docs = self.generate_hour(hour);
+        let generated = Hour {
+            hour: hour,
+            disruption: self.disruption.clone(),
+            started: started,
+            docs: docs
+        };
 
-    ::GENERATOR_RUNNING.store(false, Ordering::SeqCst);
-    ::util::send_bulk("http://localhost:9200/data/data/_bulk", &client, bulk);
+        self.end_hour();
+        Some(generated)
+    }
 }
 
-// Background thread to generate gaussian values
-fn start_normal_generator() -> Receiver<f64> {
+/// The generated tuples of a timeline, one at a time
+pub struct Records<'a> {
+    timeline: Timeline<'a>,
+    docs: vec::IntoIter<TupleResult>
+}
 
-    // sync channel will buffer until full then block the thread
-    let (tx, rx) = sync_channel(32768);
+impl<'a> Iterator for Records<'a> {
+    type Item = TupleResult;
 
-    debug!("Starting gaussian thread...");
-    thread::spawn(move|| {
-        ::GENERATOR_RUNNING.store(true, Ordering::SeqCst);
-        let normal = Normal::new(0.0, 1.0);
-        let mut counter = 0;
+    fn next(&mut self) -> Option<TupleResult> {
         loop {
-            let v = normal.ind_sample(&mut thread_rng());
-            match tx.try_send(v) {
-                Ok(_) => {},
-                Err(_) => thread::sleep_ms(50)
+            if let Some(doc) = self.docs.next() {
+                return Some(doc);
+            }
+            match self.timeline.next() {
+                Some(hour) => self.docs = hour.docs.into_iter(),
+                None => return None
             }
+        }
+    }
+}
+
+// Collects generated tuples into bulks for the sink, cut at `es.bulk_size` documents or
+// `es.bulk_bytes` of bulk body, whichever comes first, or smaller if the sink asks.  To
+// cut at a number of bytes the body is written as documents are pushed, and handed to
+// the sink with them
+struct Bulker {
+    sink: Box<Sink>,
+    limits: (usize, usize),
+    bulk_size: usize,
+    bulk_bytes: usize,
+    bulk: Vec<TupleResult>,
+    body: Vec<u8>
+}
+
+impl Bulker {
+    fn new(config: &Config, sink: Box<Sink>) -> Bulker {
+        let limits = (config.es.bulk_size, config.es.bulk_bytes);
+        let (bulk_size, bulk_bytes) = sink.bulk_size().unwrap_or(limits);
+        Bulker {
+            sink: sink,
+            limits: limits,
+            bulk_size: bulk_size,
+            bulk_bytes: bulk_bytes,
+            bulk: Vec::with_capacity(bulk_size),
+            body: Vec::with_capacity(bulk_bytes)
+        }
+    }
 
-            if counter > 1024 {
-                counter = 0;
-                if ::GENERATOR_RUNNING.load(Ordering::SeqCst) == false {
-                    break;
+    fn push(&mut self, docs: Vec<TupleResult>) {
+        for doc in docs {
+            if self.bulk_bytes > 0 {
+                let start = self.body.len();
+                es::write_bulk_doc(&mut self.body, &doc).unwrap();
+
+                // The document that takes the body over the limit starts the next one.  A
+                // document bigger than the limit still goes, in a bulk of its own
+                if self.bulk.len() > 0 && self.body.len() > self.bulk_bytes {
+                    let next = self.body.split_off(start);
+                    self.flush();
+                    if self.bulk_bytes > 0 {
+                        self.body.extend_from_slice(&next);
+                    }
                 }
             }
 
-            counter += 1;
+            self.bulk.push(doc);
+            if self.bulk.len() >= self.bulk_size {
+                self.flush();
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.bulk.len() == 0 {
+            return;
+        }
+
+        let bulk = mem::replace(&mut self.bulk, Vec::new());
+        match self.body.len() {
+            0 => self.sink.send(bulk),
+            _ => {
+                let body = mem::replace(&mut self.body, Vec::new());
+                self.sink.send_body(bulk, body);
+            }
         }
-    });
 
-    rx
+        let (bulk_size, bulk_bytes) = self.sink.bulk_size().unwrap_or(self.limits);
+        self.bulk_size = bulk_size;
+        self.bulk_bytes = bulk_bytes;
+        self.bulk.reserve(bulk_size);
+        self.body.reserve(bulk_bytes);
+    }
+
+    // Send whatever is pending, and wait for it to land
+    fn sync(&mut self) -> Result<(), String> {
+        self.flush();
+        self.sink.finish()
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        self.sync()
+    }
+}
+
+// One of the independent random streams of the run seeded with `seed`.  The streams'
+// seeds are spread apart with SplitMix64, as neighbouring xorshift seeds start out alike
+fn stream(seed: u64, stream: u64) -> XorShiftRng {
+    let mut state = seed ^ stream.wrapping_mul(0x9E3779B97F4A7C15);
+    let mut words = [0u32; 4];
+    for i in 0..2 {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z = z ^ (z >> 31);
+        words[i * 2] = z as u32;
+        words[i * 2 + 1] = (z >> 32) as u32;
+    }
+
+    // xorshift is stuck at zero with an all-zero seed
+    if words == [0; 4] {
+        words[0] = 1;
+    }
+    XorShiftRng::from_seed(words)
+}
+
+// The `tuples` gaussians of a node for an hour.  Every (hour, node) draws from a stream
+// of its own, so its values don't depend on where the timeline started, or on which
+// thread sampled it
+fn sample_node(seed: u64, hour: usize, node: usize, nodes: usize, tuples: usize) -> Vec<f64> {
+    let mut block = vec![0.0; tuples];
+    let mut rng = stream(seed, HOURS + (hour * nodes + node) as u64);
+    gaussian::fill(&mut rng, &mut block);
+    block
 }
 
 // Generate a timeline of simulated disruptions to seed
-fn generate_disruptions(config: &Config, rng: &mut ThreadRng) -> HashMap<usize, (Disruption, usize)> {
+fn generate_disruptions<R: Rng>(config: &Config, rng: &mut R) -> HashMap<usize, (Disruption, usize)> {
 
     debug!("Generating disruptions...");
     let mut disruptions = HashMap::with_capacity(config.disruptions);
     for _ in 0..config.disruptions {
         // Disruptions start after the 48th hour, and can last 2-24 hours long
         let (start, length) = (rng.gen_range(48, config.hours - 24), rng.gen_range(2, 24));
-        let disruption = match rng.gen_range(1,4) {
-            // Node disruption: all (metric,queries) on the node are disrupted
-            1 => {
-                let id = rng.gen_range(0, config.nodes);
-                debug!("Node Disruption: {}-{} [{:?}]", start, start+length, id);
-                Disruption::Node(id)
-            },
-            // Query disruption: all (metric, node) with the query(ies) are disrupted
-            2 => {
-                // random number of queries from 1 to one-tenth of the total queries
-                let num_queries = rng.gen_range(1,config.queries/10);
-                let mut v: Vec<usize> = (0..num_queries).map(|_| rng.gen_range(0, config.queries)).collect();
-                v.sort();
-                v.dedup();
-                debug!("Query Disruption: {}-{} [{:?}]", start, start+length, v);
-                Disruption::Query(v)
-            },
-            // Metric disruption: all (node,query) with the metric are disrupted
-            _ => {
-                // one-to-all metrics disrupted
-                let num_metrics = rng.gen_range(1,config.metrics);
-                let mut v: Vec<usize> = (0..num_metrics).map(|_| rng.gen_range(0, config.metrics)).collect();
-                v.sort();
-                v.dedup();
-                debug!("Metric Disruption: {}-{} [{:?}]", start, start+length, v);
-                Disruption::Metric(v)
-            }
-        };
+        let disruption = generate_disruption(config, rng, start, length);
         disruptions.insert(start, (disruption, length));
     }
 
     disruptions
 }
 
+// Pick a random disruption type and the node/queries/metrics it affects
+fn generate_disruption<R: Rng>(config: &Config, rng: &mut R, start: usize, length: usize) -> Disruption {
+    match rng.gen_range(1,4) {
+        // Node disruption: all (metric,queries) on the node are disrupted
+        1 => {
+            let id = rng.gen_range(0, config.nodes);
+            debug!("Node Disruption: {}-{} [{:?}]", start, start+length, id);
+            Disruption::Node(id)
+        },
+        // Query disruption: all (metric, node) with the query(ies) are disrupted
+        2 => {
+            // random number of queries from 1 to one-tenth of the total queries
+            let num_queries = rng.gen_range(1,config.queries/10);
+            let mut v: Vec<usize> = (0..num_queries).map(|_| rng.gen_range(0, config.queries)).collect();
+            v.sort();
+            v.dedup();
+            debug!("Query Disruption: {}-{} [{:?}]", start, start+length, v);
+            Disruption::Query(v)
+        },
+        // Metric disruption: all (node,query) with the metric are disrupted
+        _ => {
+            // one-to-all metrics disrupted
+            let num_metrics = rng.gen_range(1,config.metrics);
+            let mut v: Vec<usize> = (0..num_metrics).map(|_| rng.gen_range(0, config.metrics)).collect();
+            v.sort();
+            v.dedup();
+            debug!("Metric Disruption: {}-{} [{:?}]", start, start+length, v);
+            Disruption::Metric(v)
+        }
+    }
+}
+
 // Generate the distributions for each (node, query, metric) tuple
-fn generate_distributions(config: &Config, rng: &mut ThreadRng)
-                            -> HashMap<(usize, usize, usize), (NormalParams, NormalParams)> {
+fn generate_distributions<R: Rng>(config: &Config, rng: &mut R) -> Vec<(NormalParams, NormalParams)> {
     // Generate the distributions for each (node, query, metric) tuple
     debug!("generating distributions per (node,query,metric) tuple...");
-    let mut distributions = HashMap::with_capacity(config.nodes * config.queries * config.metrics);
+    let mut distributions = Vec::with_capacity(config.nodes * config.queries * config.metrics);
     for node in 0..config.nodes {
         for query in 0..config.queries {
             for metric in 0..config.metrics {
@@ -249,7 +848,7 @@ fn generate_distributions(config: &Config, rng: &mut ThreadRng)
                     std: rng.gen_range(config.disrupted_distribution.min_std, config.disrupted_distribution.max_std)
                 };
 
-                distributions.insert((node, query, metric), (regular, disrupted));
+                distributions.push((regular, disrupted));
             }
         }
     }
@@ -0,0 +1,181 @@
+//! Simulates the metrics of a cluster, with disruptions injected into them, indexes them
+//! into Elasticsearch and computes the "hotcloud": how surprising every metric was, hour
+//! by hour, so the disruptions can be seen at a glance.
+//!
+//! The `hotcloud` binary is a thin wrapper around this crate.  Harnesses can embed the
+//! pieces directly: load a `Config`, iterate over a `Generator`'s timeline or send it to
+//! any `Sink`, and score series with the `Model`s and `Scorer`s of the detectors.
+
+#![feature(custom_derive, plugin)]
+
+#[macro_use] extern crate log;
+extern crate toml;
+extern crate rustc_serialize;
+extern crate rand;
+extern crate hyper;
+extern crate threadpool;
+extern crate time;
+extern crate chrono;
+extern crate termion;
+extern crate libc;
+
+pub mod config;
+pub mod query;
+mod util;
+pub mod gaussian;
+pub mod generator;
+pub mod model;
+pub mod detect;
+pub mod score;
+pub mod changepoint;
+pub mod attribution;
+pub mod node;
+pub mod es;
+pub mod render;
+pub mod dashboard;
+pub mod checkpoint;
+pub mod shutdown;
+pub mod stats;
+pub mod metrics;
+pub mod batch;
+
+pub use config::Config;
+pub use es::{Cluster, Doc};
+pub use generator::{Generator, Timeline, Hour, Sink, EsSink, JsonSink, TupleResult};
+pub use model::Model;
+pub use score::Scorer;
+pub use dashboard::{Dashboard, Reporter};
+pub use checkpoint::Checkpoint;
+pub use shutdown::Shutdown;
+pub use stats::{Stats, Summary};
+
+use std::sync::Arc;
+use std::thread;
+
+/// What a simulated disruption hits: every (metric, query) of a node, every (node, metric)
+/// of some queries, or every (node, query) of some metrics
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub enum Disruption {
+    Node(usize),
+    Query(Vec<usize>),
+    Metric(Vec<usize>)
+}
+
+/// Delete and recreate every index, and register the search templates
+pub fn reset_indices(es: &Arc<Cluster>, config: &Config) {
+    debug!("Resetting index...");
+    for index in es.indices() {
+        // The mappings in the config, if any, replace the generated ones
+        let body = match index {
+            "data" if config.es.mapping.len() > 0 => config.es.mapping.clone(),
+            "hotcloud" if config.es.hotcloudmapping.len() > 0 => config.es.hotcloudmapping.clone(),
+            _ => es.index_body(index).to_string()
+        };
+
+        es.delete(index);
+        es.put(index, &body);
+    }
+
+    es.get("_cluster/health?wait_for_status=yellow");
+    es.put_template("hotcloud", query::template(config, es.dialect));
+    es.put_template("hotcloud_node", node::template(config, es.dialect));
+}
+
+/// Generate the full history, then compute the change points and the hotcloud over it.
+/// Progress is saved to `checkpoint.path` along the way, and a run interrupted part-way
+/// through carries on from there instead of resetting the indices.  Returns early, with
+/// everything sent so far indexed and saved to the checkpoint, once `shutdown` is
+/// requested.  Fails if the checkpoint can't be read or was saved by another scenario
+pub fn run(es: &Arc<Cluster>, config: Config, reporter: &Reporter, shutdown: &Shutdown) -> Result<(), String> {
+    let checkpoint = Arc::new(try!(Checkpoint::open(&config)));
+    if checkpoint.resumed() {
+        let progress = checkpoint.progress();
+        info!("Resuming from {}: {} of {} hours generated", config.checkpoint.path, progress.generated, config.hours);
+    } else {
+        reset_indices(es, &config);
+    }
+
+    let generated = Generator::new(&config)
+        .reporter(reporter)
+        .checkpoint(&checkpoint)
+        .shutdown(shutdown)
+        .run(Box::new(EsSink::new(es, &config, reporter)));
+    if let Err(err) = generated {
+        stopped(&checkpoint);
+        return Err(err);
+    }
+    if shutdown.requested() {
+        return Ok(stopped(&checkpoint));
+    }
+
+    // Change points that couldn't be computed are left for the next run, after the hotcloud
+    es.refresh(&[Doc::Data]);
+    let changepoints = config.changepoint.enabled;
+    if changepoints && !checkpoint.progress().changepoints {
+        let found = changepoint::run_changepoints(es, &config, shutdown);
+        if shutdown.requested() {
+            return Ok(stopped(&checkpoint));
+        }
+        match found {
+            Ok(()) => checkpoint.update(|progress| progress.changepoints = true),
+            Err(err) => error!("Could not compute the change points: {}", err)
+        }
+    }
+
+    query::run_hotcloud(es, config, reporter, &checkpoint, shutdown);
+    if shutdown.requested() {
+        return Ok(stopped(&checkpoint));
+    }
+
+    // Hours whose searches kept failing are left for the next run
+    let unscored = unscored(&checkpoint);
+    if unscored > 0 {
+        stopped(&checkpoint);
+        return Err(format!("{} hours could not be scored", unscored));
+    }
+    if changepoints && !checkpoint.progress().changepoints {
+        stopped(&checkpoint);
+        return Err("The change points could not be computed".to_owned());
+    }
+    checkpoint.finish();
+    Ok(())
+}
+
+fn unscored(checkpoint: &Checkpoint) -> usize {
+    checkpoint.progress().batches.iter().map(|batch| batch.end - batch.next).sum()
+}
+
+// Say how far an interrupted run got, and how to carry on
+fn stopped(checkpoint: &Checkpoint) {
+    let progress = checkpoint.progress();
+    let unscored = unscored(checkpoint);
+    info!("Stopped after generating {} hours, change points {}, {}",
+          progress.generated,
+          if progress.changepoints { "done" } else { "pending" },
+          match progress.batches.len() {
+              0 => "hotcloud not started".to_owned(),
+              _ => format!("{} hours left to score", unscored)
+          });
+    match checkpoint.path().len() {
+        0 => info!("checkpoint.path is empty, so the next run starts over"),
+        _ => info!("Progress saved to {}, run again to resume", checkpoint.path())
+    }
+}
+
+/// Generate data in real time, following it with the hotcloud, until `shutdown` is requested
+pub fn stream(es: &Arc<Cluster>, config: Config, reporter: &Reporter, shutdown: &Shutdown) {
+    reset_indices(es, &config);
+
+    let config = Arc::new(config);
+    let (es_clone, config_clone, reporter_clone) = (es.clone(), config.clone(), reporter.clone());
+    let shutdown_clone = shutdown.clone();
+    let follow = thread::spawn(move || {
+        query::follow_hotcloud(&es_clone, &config_clone, &reporter_clone, &shutdown_clone);
+    });
+
+    Generator::new(&config)
+        .reporter(reporter)
+        .shutdown(shutdown)
+        .stream(Box::new(EsSink::new(es, &config, reporter)));
+    let _ = follow.join();
+}
@@ -1,58 +1,135 @@
-#![feature(custom_derive, plugin)]
-
-#[macro_use] extern crate log;
+extern crate hotcloud;
 extern crate env_logger;
-extern crate toml;
-extern crate rustc_serialize;
-extern crate rand;
-extern crate hyper;
-extern crate threadpool;
-extern crate time;
-extern crate chrono;
-
-mod config;
-mod query;
-mod util;
-mod generator;
-
-use config::Config;
-use hyper::Client;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering, ATOMIC_USIZE_INIT, ATOMIC_BOOL_INIT};
-use std::thread;
-
-pub static ACTIVE_THREADS: AtomicUsize = ATOMIC_USIZE_INIT;
-pub static GENERATOR_RUNNING: AtomicBool = ATOMIC_BOOL_INIT;
 
-pub enum Disruption {
-    Node(usize),
-    Query(Vec<usize>),
-    Metric(Vec<usize>)
-}
+use hotcloud::{config, query, render, shutdown, Cluster, Dashboard, Reporter, Shutdown, Summary};
+use hotcloud::config::{Config, Layers};
+use hotcloud::metrics::Endpoint;
+use std::sync::Arc;
+use std::env;
+use std::process;
 
 fn main() {
     env_logger::init().unwrap();
 
-    let config = Config::parse("config.toml".to_owned());
-    let client = Arc::new(Client::new());
+    let command = env::args().nth(1).unwrap_or("run".to_owned());
+
+    if command == "check-config" || command == "show-config" {
+        return check_config(&command);
+    }
+
+    let config = load_config("config.toml");
+    let shutdown = Shutdown::new();
+    shutdown::install(&shutdown);
 
-    debug!("Resetting index...");
-    client.delete("http://localhost:9200/data/").send();
-    client.put("http://localhost:9200/data/").body(&config.es.mapping).send();
+    // Rendering can work from files alone, everything else needs the cluster
+    let es = match &*command {
+        "run" | "stream" | "follow" => Some(connect(&config)),
+        _ => None
+    };
 
-    client.delete("http://localhost:9200/hotcloud/").send();
-    client.put("http://localhost:9200/hotcloud/").body(&config.es.hotcloudmapping).send();
+    // Only a batch run knows how long the timeline is
+    let dashboard = match (config.dashboard.enabled, &*command, &es) {
+        (true, "run", &Some(ref es)) => Some(Dashboard::start(&config, es, &command, Some(config.hours), &shutdown)),
+        (true, _, &Some(ref es)) => Some(Dashboard::start(&config, es, &command, None, &shutdown)),
+        _ => None
+    };
+    let reporter = dashboard.as_ref().map_or(Reporter::none(), |d| d.reporter());
 
-    client.get("http://localhost:9200/_cluster/health?wait_for_status=yellow").send();
-    client.delete("http://localhost:9200/_search/template/hotcloud").send();
-    client.post("http://localhost:9200/_search/template/hotcloud").body(&config.es.query).send();
+    let endpoint = match (config.prometheus.address.len(), &es) {
+        (0, _) | (_, &None) => None,
+        (_, &Some(ref es)) => {
+            let hours = if command == "run" { Some(config.hours) } else { None };
+            Some(Endpoint::start(&config.prometheus.address, es, &reporter, hours).unwrap_or_else(|err| {
+                println!("{}", err);
+                process::exit(1);
+            }))
+        }
+    };
 
-    generator::generate_timeline(&client, &config, false);
+    let summary = config.summary.path.clone();
+    let result = match (&*command, &es) {
+        ("run", &Some(ref es)) => hotcloud::run(es, config, &reporter, &shutdown),
+        ("stream", &Some(ref es)) => Ok(hotcloud::stream(es, config, &reporter, &shutdown)),
+        ("follow", &Some(ref es)) => Ok(query::follow_hotcloud(es, &config, &reporter, &shutdown)),
+        ("render", _) => Ok(render::render(&config)),
+        _ => {
+            println!("Usage: hotcloud [run|stream|follow|render|check-config|show-config]");
+            println!("    run     generate the full history, then compute the hotcloud (default)");
+            println!("    stream  generate data in real time, forever, following it with the hotcloud");
+            println!("    follow  keep the hotcloud up to date as new data is indexed");
+            println!("    render  draw the hotcloud as a heatmap, with the disruptions overlaid");
+            println!("    check-config  list everything wrong with config.toml, without running anything");
+            println!("    show-config   print every resolved setting and the layer it came from");
+            Ok(())
+        }
+    };
 
-    while ::ACTIVE_THREADS.load(Ordering::SeqCst) > 0 {
-        thread::sleep_ms(500);
+    if let Some(dashboard) = dashboard {
+        dashboard.finish();
+    }
+    if let Some(es) = es {
+        print_summary(&es.stats.summary(), &summary);
     }
+    drop(endpoint);
+    if let Err(err) = result {
+        println!("{}", err);
+        process::exit(1);
+    }
+    if shutdown.requested() {
+        process::exit(shutdown.status());
+    }
+}
+
+// Load the config, printing everything wrong with it and exiting if it is invalid
+fn load_config(path: &str) -> Config {
+    Config::load(path).unwrap_or_else(|errors| {
+        config::report(path, &errors);
+        process::exit(1);
+    })
+}
+
+// Print the summary of the run, and save it as JSON unless summary.path is empty
+fn print_summary(summary: &Summary, path: &str) {
+    println!("{}", summary);
+    if path.len() > 0 {
+        if let Err(err) = summary.save(path) {
+            println!("Could not write the summary to {}: {}", path, err);
+        }
+    }
+}
+
+// Report on the config without running anything, first listing every resolved setting
+// for show-config
+fn check_config(command: &str) {
+    let path = "config.toml";
+    let layers = Layers::read(path).unwrap_or_else(|errors| {
+        config::report(path, &errors);
+        process::exit(1);
+    });
+
+    if command == "show-config" {
+        let resolved = layers.resolved();
+        let lines: Vec<String> = resolved.iter().map(|&(ref field, value, _)| format!("{} = {}", field, value)).collect();
+        let width = lines.iter().map(|line| line.len()).max().unwrap_or(0).min(60);
+        for (line, &(_, _, source)) in lines.iter().zip(&resolved) {
+            println!("{:2$}  # {}", line, source, width);
+        }
+        println!("");
+    }
+
+    match layers.decode() {
+        Ok(_) => println!("{} is valid", path),
+        Err(errors) => {
+            config::report(path, &errors);
+            process::exit(1);
+        }
+    }
+}
 
-    let _ = client.put("http://localhost:9200/data/_refresh").send();
-    query::run_hotcloud(&client, config);
+// Connect to the cluster, printing the error and exiting if it can't be reached
+fn connect(config: &Config) -> Arc<Cluster> {
+    Arc::new(Cluster::connect(&config.es.url).unwrap_or_else(|err| {
+        println!("{}", err);
+        process::exit(1);
+    }))
 }
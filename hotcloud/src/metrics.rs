@@ -0,0 +1,109 @@
+use dashboard::Reporter;
+use es::Cluster;
+use hyper::header::ContentType;
+use hyper::net::Fresh;
+use hyper::server::{self, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+
+/// A `/metrics` endpoint in the Prometheus text format, so a long run can be watched
+/// from Grafana: how far the generator has got, the bulks queued and in flight, bulk
+/// errors, and how many hours the hotcloud has scored
+pub struct Endpoint {
+    listening: Listening
+}
+
+impl Endpoint {
+    /// Serve the metrics of a run on `address` (`host:port`).  `hours` is the length of
+    /// the timeline, if it has one
+    pub fn start(address: &str, es: &Arc<Cluster>, reporter: &Reporter, hours: Option<usize>) -> Result<Endpoint, String> {
+        let handler = Handler {
+            es: es.clone(),
+            reporter: Mutex::new(reporter.clone()),
+            hours: hours
+        };
+
+        let server = try!(Server::http(address).map_err(|err| format!("Could not listen on {}: {}", address, err)));
+        let listening = try!(server.handle_threads(handler, 2).map_err(|err| format!("Could not listen on {}: {}", address, err)));
+        info!("Serving metrics on http://{}/metrics", listening.socket);
+        Ok(Endpoint {
+            listening: listening
+        })
+    }
+
+    /// The address actually listened on, e.g. with port 0 picked by the system
+    pub fn address(&self) -> String {
+        self.listening.socket.to_string()
+    }
+}
+
+// Dropping a `Listening` waits for the server to stop, which it never does
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let _ = self.listening.close();
+    }
+}
+
+struct Handler {
+    es: Arc<Cluster>,
+    reporter: Mutex<Reporter>,
+    hours: Option<usize>
+}
+
+impl server::Handler for Handler {
+    fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, mut res: Response<'a, Fresh>) {
+        match req.uri {
+            RequestUri::AbsolutePath(ref path) if path == "/metrics" => {
+                res.headers_mut().set(ContentType("text/plain; version=0.0.4".parse().unwrap()));
+                let _ = res.send(self.render().as_bytes());
+            },
+            _ => {
+                *res.status_mut() = StatusCode::NotFound;
+                let _ = res.send(b"Metrics are served on /metrics\n");
+            }
+        }
+    }
+}
+
+impl Handler {
+    fn render(&self) -> String {
+        let summary = self.es.stats.summary();
+        let mut out = String::new();
+
+        metric(&mut out, "hotcloud_generated_hours", "counter", "Hours of data generated",
+               self.reporter.lock().unwrap().generated() as f64);
+        if let Some(hours) = self.hours {
+            metric(&mut out, "hotcloud_timeline_hours", "gauge", "Hours in the timeline being generated and scored", hours as f64);
+        }
+
+        metric(&mut out, "hotcloud_bulks_queued", "gauge", "Bulks of data waiting for a thread to send them",
+               self.es.queued.load(Ordering::SeqCst) as f64);
+        metric(&mut out, "hotcloud_bulks_in_flight", "gauge", "Bulks being sent to the cluster",
+               self.es.in_flight.load(Ordering::SeqCst) as f64);
+        metric(&mut out, "hotcloud_bulks_total", "counter", "Bulks indexed", summary.bulks.count as f64);
+        metric(&mut out, "hotcloud_bulk_retries_total", "counter", "Bulks sent again after being rejected or failing to arrive",
+               summary.retries as f64);
+        metric(&mut out, "hotcloud_bulk_errors_total", "counter", "Bulks given up on", summary.failed_bulks as f64);
+        metric(&mut out, "hotcloud_failed_docs_total", "counter", "Documents that failed to be indexed", summary.failed_docs as f64);
+        metric(&mut out, "hotcloud_indexed_bytes_total", "counter", "Bytes of bulk bodies indexed", summary.bytes as f64);
+
+        let _ = writeln!(out, "# HELP hotcloud_indexed_docs_total Documents indexed");
+        let _ = writeln!(out, "# TYPE hotcloud_indexed_docs_total counter");
+        for (index, docs) in &summary.docs {
+            let _ = writeln!(out, "hotcloud_indexed_docs_total{{index=\"{}\"}} {}", index, docs);
+        }
+
+        metric(&mut out, "hotcloud_scored_hours", "counter", "Hours the hotcloud has been queried and scored for",
+               summary.queries.count as f64);
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
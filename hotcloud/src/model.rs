@@ -0,0 +1,188 @@
+use config::Detect;
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+
+// Padding added to values by multiplicative Holt-Winters, which can't handle zeros
+const PAD: f64 = 0.0000000001;
+
+/// Smoothing models used to build the "expected" baseline that surprise is measured
+/// against.  These mirror the models offered by the ES `moving_avg` aggregation so the
+/// native path and the search template agree.
+#[derive(Debug, Clone)]
+pub enum Model {
+    Simple,
+    Linear,
+    Ewma { alpha: f64 },
+    Holt { alpha: f64, beta: f64 },
+    HoltWinters { alpha: f64, beta: f64, gamma: f64, period: usize, multiplicative: bool }
+}
+
+impl Model {
+
+    /// Build the model described by the `[detect]` section of the config
+    pub fn new(detect: &Detect) -> Result<Model, String> {
+        let model = match &*detect.model {
+            "simple" => Model::Simple,
+            "linear" => Model::Linear,
+            "ewma" => Model::Ewma { alpha: detect.alpha },
+            "holt" => Model::Holt { alpha: detect.alpha, beta: detect.beta },
+            "holt_winters" => Model::HoltWinters {
+                alpha: detect.alpha,
+                beta: detect.beta,
+                gamma: detect.gamma,
+                period: detect.period,
+                multiplicative: match &*detect.seasonality {
+                    "add" => false,
+                    "mult" => true,
+                    other => return Err(format!("Unknown seasonality [{}], expected add or mult", other))
+                }
+            },
+            other => return Err(format!("Unknown model [{}], expected simple, linear, ewma, holt or holt_winters", other))
+        };
+
+        if let Model::HoltWinters { period, .. } = model {
+            if period == 0 || detect.window < period * 2 {
+                return Err(format!("holt_winters needs a window of at least two periods ({} < 2 * {})",
+                                   detect.window, period));
+            }
+        }
+
+        Ok(model)
+    }
+
+    /// The `model` name used by ES
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Model::Simple => "simple",
+            Model::Linear => "linear",
+            Model::Ewma { .. } => "ewma",
+            Model::Holt { .. } => "holt",
+            Model::HoltWinters { .. } => "holt_winters"
+        }
+    }
+
+    /// The `settings` object of the ES `moving_avg` aggregation, if the model has any
+    pub fn settings(&self) -> Option<Json> {
+        let mut settings = BTreeMap::new();
+        match *self {
+            Model::Simple | Model::Linear => return None,
+            Model::Ewma { alpha } => {
+                settings.insert("alpha".to_owned(), Json::F64(alpha));
+            },
+            Model::Holt { alpha, beta } => {
+                settings.insert("alpha".to_owned(), Json::F64(alpha));
+                settings.insert("beta".to_owned(), Json::F64(beta));
+            },
+            Model::HoltWinters { alpha, beta, gamma, period, multiplicative } => {
+                settings.insert("alpha".to_owned(), Json::F64(alpha));
+                settings.insert("beta".to_owned(), Json::F64(beta));
+                settings.insert("gamma".to_owned(), Json::F64(gamma));
+                settings.insert("period".to_owned(), Json::U64(period as u64));
+                settings.insert("type".to_owned(), Json::String(if multiplicative { "mult" } else { "add" }.to_owned()));
+                settings.insert("pad".to_owned(), Json::Boolean(multiplicative));
+            }
+        }
+        Some(Json::Object(settings))
+    }
+
+    /// The Painless script of the equivalent `moving_fn` aggregation, for clusters
+    /// without `moving_avg`
+    pub fn moving_fn(&self) -> String {
+        match *self {
+            Model::Simple => "MovingFunctions.unweightedAvg(values)".to_owned(),
+            Model::Linear => "MovingFunctions.linearWeightedAvg(values)".to_owned(),
+            Model::Ewma { alpha } => format!("MovingFunctions.ewma(values, {:?})", alpha),
+            Model::Holt { alpha, beta } => format!("MovingFunctions.holt(values, {:?}, {:?})", alpha, beta),
+            // holtWinters throws rather than returning NaN until it has two periods
+            Model::HoltWinters { alpha, beta, gamma, period, multiplicative } => {
+                format!("values.length < {} ? Double.NaN : MovingFunctions.holtWinters(values, {:?}, {:?}, {:?}, {}, {})",
+                        period * 2, alpha, beta, gamma, period, multiplicative)
+            }
+        }
+    }
+
+    /// Predict the next value from a window of previous values (oldest first).
+    /// Returns None if the window doesn't hold enough data for the model
+    pub fn predict(&self, values: &[f64]) -> Option<f64> {
+        if values.len() == 0 {
+            return None;
+        }
+
+        match *self {
+            Model::Simple => Some(values.iter().fold(0f64, |acc, v| acc + v) / values.len() as f64),
+
+            // Linearly increasing weights, the oldest value has a weight of one
+            Model::Linear => {
+                let (mut avg, mut total) = (0f64, 0f64);
+                for (i, v) in values.iter().enumerate() {
+                    let weight = (i + 1) as f64;
+                    avg += v * weight;
+                    total += weight;
+                }
+                Some(avg / total)
+            },
+
+            Model::Ewma { alpha } => {
+                let mut avg = values[0];
+                for v in &values[1..] {
+                    avg = (v * alpha) + (avg * (1.0 - alpha));
+                }
+                Some(avg)
+            },
+
+            // Double exponential smoothing: a level plus a trend
+            Model::Holt { alpha, beta } => {
+                let (mut s, mut b) = (values[0], 0f64);
+                if values.len() > 1 {
+                    b = values[1] - values[0];
+                }
+
+                for v in &values[1..] {
+                    let last_s = s;
+                    s = alpha * v + (1.0 - alpha) * (s + b);
+                    b = beta * (s - last_s) + (1.0 - beta) * b;
+                }
+                Some(s + b)
+            },
+
+            // Triple exponential smoothing: level, trend and a seasonal component
+            Model::HoltWinters { alpha, beta, gamma, period, multiplicative } => {
+                if values.len() < period * 2 {
+                    return None;
+                }
+
+                let pad = if multiplicative { PAD } else { 0.0 };
+                let values: Vec<f64> = values.iter().map(|v| v + pad).collect();
+
+                // Seed the level from the first period, the trend from the difference
+                // between the first two periods
+                let first = values[..period].iter().fold(0f64, |acc, v| acc + v) / period as f64;
+                let second = values[period..period * 2].iter().fold(0f64, |acc, v| acc + v) / period as f64;
+                let (mut s, mut b) = (first, (second - first) / period as f64);
+
+                let mut seasonal: Vec<f64> = values[..period].iter().map(|v| {
+                    if multiplicative { v / first } else { v - first }
+                }).collect();
+
+                for i in period..values.len() {
+                    let (last_s, season) = (s, seasonal[i - period]);
+                    if multiplicative {
+                        s = alpha * (values[i] / season) + (1.0 - alpha) * (s + b);
+                        b = beta * (s - last_s) + (1.0 - beta) * b;
+                        seasonal.push(gamma * (values[i] / s) + (1.0 - gamma) * season);
+                    } else {
+                        s = alpha * (values[i] - season) + (1.0 - alpha) * (s + b);
+                        b = beta * (s - last_s) + (1.0 - beta) * b;
+                        seasonal.push(gamma * (values[i] - s) + (1.0 - gamma) * season);
+                    }
+                }
+
+                let season = seasonal[values.len() - period];
+                match multiplicative {
+                    true => Some((s + b) * season - pad),
+                    false => Some(s + b + season)
+                }
+            }
+        }
+    }
+}
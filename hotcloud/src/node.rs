@@ -0,0 +1,236 @@
+use config::Config;
+use detect::{self, Series};
+use model::Model;
+use score::Scorer;
+use query::{self, OptionalValue, PercentileValues, SearchParams};
+use es::{Agg, Cluster, Dialect, Doc, Document, Search};
+use rustc_serialize::json::{self, Json};
+use std::sync::Arc;
+use chrono::{Duration, UTC};
+use chrono::offset::TimeZone;
+
+/// The hotcloud value of a single node for a metric/hour
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct NodeResult {
+    pub metric: usize,
+    pub node: usize,
+    pub hour: String,
+    /// Largest surprise of the node's series: the hourly average of all its values, over
+    /// every query
+    pub value: f64,
+    /// Headline percentile (the first of `detect.percentiles`) of the largest surprise
+    /// of each (node,query) series
+    pub query_value: Option<f64>
+}
+
+impl Document for NodeResult {
+    fn id(&self) -> String {
+        format!("{}_{}_{}", self.hour, self.metric, self.node)
+    }
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeResponse {
+    pub aggregations: NodeAggs
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeAggs {
+    pub metrics: NodeMetrics
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeMetrics {
+    pub buckets: Vec<NodeMetricBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeMetricBucket {
+    pub key: usize,
+    pub nodes: NodeBuckets
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeBuckets {
+    pub buckets: Vec<NodeBucket>
+}
+
+// Filled in by the search template
+#[derive(RustcDecodable, Debug)]
+pub struct NodeBucket {
+    pub key: usize,
+    pub largest_surprise: OptionalValue,
+    pub surprise_percentiles: Option<PercentileValues>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeSeriesResponse {
+    pub aggregations: NodeSeriesAggs
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeSeriesAggs {
+    pub metrics: NodeSeriesMetrics
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeSeriesMetrics {
+    pub buckets: Vec<NodeSeriesMetricBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeSeriesMetricBucket {
+    pub key: usize,
+    pub nodes: NodeSeriesBuckets
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeSeriesBuckets {
+    pub buckets: Vec<NodeSeriesBucket>
+}
+
+// Filled in by the native fetch: the raw hourly averages
+#[derive(RustcDecodable, Debug)]
+pub struct NodeSeriesBucket {
+    pub key: usize,
+    pub series: Series,
+    pub queries: Option<NodeQueries>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeQueries {
+    pub buckets: Vec<NodeQueryBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct NodeQueryBucket {
+    pub key: usize,
+    pub series: Series
+}
+
+/// The node-dimension search template: metric -> node -> hour, plus
+/// metric -> node x query when `node.by_query` is set
+pub fn template(config: &Config, dialect: Dialect) -> Json {
+    let (model, scorer) = query::detectors(config);
+
+    let mut aggs = vec![
+        ("series", query::series_agg(config, &model, &scorer)),
+        ("largest_surprise", Agg::MaxBucket("series.surprise"))
+    ];
+    if config.node.by_query {
+        aggs.push(("queries", Agg::Terms { field: "query", size: config.queries, aggs: vec![
+            ("series", query::series_agg(config, &model, &scorer)),
+            ("largest_surprise", Agg::MaxBucket("series.surprise"))
+        ]}));
+        aggs.push(("surprise_percentiles", Agg::PercentilesBucket {
+            path: "queries>largest_surprise",
+            percents: vec![config.detect.percentiles[0]]
+        }));
+    }
+
+    Search {
+        filters: vec![SearchParams::placeholders().filter()],
+        aggs: vec![("metrics", Agg::Terms { field: "metric", size: config.metrics, aggs: vec![
+            ("nodes", Agg::Terms { field: "node", size: config.nodes, aggs: aggs })
+        ]})]
+    }.to_json(dialect)
+}
+
+// Compute the per-node hotcloud values for a single hour.  Fails if a search does
+pub fn query_hour(es: &Arc<Cluster>, config: &Config, model: &Model, scorer: &Scorer, hour: usize) -> Result<Vec<NodeResult>, String> {
+    if config.detect.native || scorer.script().is_none() {
+        return native_hour(es, config, model, scorer, hour);
+    }
+
+    let params = SearchParams::new(hour, config.detect.window).to_params();
+    let body = match es.search_template(Doc::Data, "hotcloud_node", params, "aggregations.metrics.buckets.key,aggregations.metrics.buckets.nodes.buckets.key,aggregations.**.largest_surprise.value,aggregations.metrics.buckets.nodes.buckets.surprise_percentiles") {
+        Some(body) => body,
+        None => return Err("the hotcloud_node search template failed".to_owned())
+    };
+
+    let decoded: NodeResponse = match try!(query::decode_aggregations(&body)) {
+        Some(decoded) => decoded,
+        None => return Ok(vec![])
+    };
+
+    let timestamp = to_timestamp(hour);
+    let mut results = vec![];
+    for metric in decoded.aggregations.metrics.buckets {
+        for node in metric.nodes.buckets {
+            if let Some(value) = node.largest_surprise.value {
+                results.push(NodeResult {
+                    metric: metric.key,
+                    node: node.key,
+                    hour: timestamp.clone(),
+                    value: value,
+                    query_value: node.surprise_percentiles.and_then(|p| p.values.get(config.detect.percentiles[0]))
+                });
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+// Fetch the raw per-node (and per node x query) series and score them in-process
+fn native_hour(es: &Arc<Cluster>, config: &Config, model: &Model, scorer: &Scorer, hour: usize) -> Result<Vec<NodeResult>, String> {
+    let response = match fetch_series(es, config, hour) {
+        Some(response) => response,
+        None => return Err("the search for the per-node series failed".to_owned())
+    };
+
+    let window = config.detect.window;
+    let timestamp = to_timestamp(hour);
+    let mut results = vec![];
+    for metric in response.aggregations.metrics.buckets {
+        for node in metric.nodes.buckets {
+            let averages: Vec<Option<f64>> = node.series.buckets.iter().map(|b| b.avg.value).collect();
+            let value = match detect::largest_surprise(model, scorer, window, &averages) {
+                Some(value) => value,
+                None => continue
+            };
+
+            let query_value = node.queries.and_then(|queries| {
+                let largest: Vec<f64> = queries.buckets.iter().filter_map(|query| {
+                    let averages: Vec<Option<f64>> = query.series.buckets.iter().map(|b| b.avg.value).collect();
+                    detect::largest_surprise(model, scorer, window, &averages)
+                }).collect();
+                detect::percentile(&largest, config.detect.percentiles[0])
+            });
+
+            results.push(NodeResult {
+                metric: metric.key,
+                node: node.key,
+                hour: timestamp.clone(),
+                value: value,
+                query_value: query_value
+            });
+        }
+    }
+
+    Ok(results)
+}
+
+fn fetch_series(es: &Arc<Cluster>, config: &Config, hour: usize) -> Option<NodeSeriesResponse> {
+    let params = SearchParams::new(hour, config.detect.window);
+
+    let mut aggs = vec![("series", detect::averages_agg())];
+    if config.node.by_query {
+        aggs.push(("queries", Agg::Terms { field: "query", size: config.queries, aggs: vec![
+            ("series", detect::averages_agg())
+        ]}));
+    }
+
+    let search = Search {
+        filters: vec![params.filter()],
+        aggs: vec![("metrics", Agg::Terms { field: "metric", size: config.metrics, aggs: vec![
+            ("nodes", Agg::Terms { field: "node", size: config.nodes, aggs: aggs })
+        ]})]
+    };
+
+    es.search(Doc::Data, &search, "").and_then(|body| json::decode(&body).ok())
+}
+
+fn to_timestamp(hour: usize) -> String {
+    (UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64)).format("%Y-%m-%dT%H:%M:%S").to_string()
+}
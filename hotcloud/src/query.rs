@@ -1,16 +1,28 @@
 
 
 use config::Config;
-use hyper::Client;
-use rustc_serialize::{Encodable, Encoder};
+use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
 use rustc_serialize::json::{self};
 use std::sync::Arc;
-use std::io::Read;
 use chrono::{Duration, UTC};
 use chrono::offset::TimeZone;
 use std::sync::atomic::Ordering;
 use std::thread;
-use hyper::status::StatusCode;
+use std::collections::BTreeMap;
+use rustc_serialize::json::Json;
+use model::Model;
+use score::Scorer;
+use changepoint::{self, ChangePoints};
+use attribution::{self, Contributor};
+use node::{self, NodeResult};
+use es::{Agg, Cluster, Dialect, Doc, Document, Filter, Search};
+use dashboard::{Event, Reporter};
+use checkpoint::{Batch, Checkpoint};
+use shutdown::Shutdown;
+use time::PreciseTime;
+
+// Times an hour whose searches failed is tried again before its batch is stopped
+const RETRIES: u32 = 3;
 
 #[derive(RustcDecodable, Debug)]
 pub struct Response {
@@ -30,67 +42,82 @@ pub struct MetricsAgg {
 #[derive(RustcDecodable, Debug)]
 pub struct MetricBucket {
     pub key: usize,
-    pub ninetieth_surprise: NinetiethValues
+    pub surprise_percentiles: PercentileValues,
+    pub max_surprise: Option<OptionalValue>,
+    pub mean_surprise: Option<OptionalValue>,
+    pub queries: Option<QueriesAgg>
 }
 
 #[derive(RustcDecodable, Debug)]
-pub struct DoubleValue {
-    pub value: f64
+pub struct QueriesAgg {
+    pub buckets: Vec<QueryBucket>
 }
 
 #[derive(RustcDecodable, Debug)]
-pub struct DoubleValues {
-    pub values: Vec<f64>
+pub struct QueryBucket {
+    pub key: usize,
+    pub largest_surprise: OptionalValue
 }
 
 #[derive(RustcDecodable, Debug)]
-pub struct NinetiethValues {
-    pub values: NinetiethValue
+pub struct BoundResponse {
+    pub aggregations: BoundAggs
 }
 
-#[derive(Debug)]
-pub struct NinetiethValue {
+#[derive(RustcDecodable, Debug)]
+pub struct BoundAggs {
+    pub bound: OptionalValue
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct OptionalValue {
+    pub value: Option<f64>
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct DoubleValue {
     pub value: f64
 }
 
-#[allow(unused_qualifications)]
-impl ::rustc_serialize::Decodable for NinetiethValue {
-    fn decode<__D: ::rustc_serialize::Decoder>(__arg_0: &mut __D) -> ::std::result::Result<NinetiethValue, __D::Error> {
-        __arg_0.read_struct("NinetiethValue", 1usize, |_d| -> _ {
-            ::std::result::Result::Ok(NinetiethValue{value:
-               match _d.read_struct_field("90.0", 0usize, ::rustc_serialize::Decodable::decode)
-               {
-                   ::std::result::Result::Ok(__try_var) => __try_var,
-                   ::std::result::Result::Err(__try_var) => return ::std::result::Result::Err(__try_var),
-               }
-           ,})
-       })
-   }
+#[derive(RustcDecodable, Debug)]
+pub struct DoubleValues {
+    pub values: Vec<f64>
 }
 
 #[derive(RustcDecodable, Debug)]
-pub struct MedianValues {
-    pub values: MedianValue
+pub struct PercentileValues {
+    pub values: PercentileMap
 }
 
+/// The `values` of a percentiles aggregation, keyed by the requested percents
+/// ("90.0", "99.9", ...).  Percentiles without a value are left out
 #[derive(Debug)]
-pub struct MedianValue {
-    pub value: f64
+pub struct PercentileMap {
+    pub values: Vec<(f64, f64)>
+}
+
+impl PercentileMap {
+    pub fn get(&self, percent: f64) -> Option<f64> {
+        self.values.iter().find(|&&(p, _)| p == percent).map(|&(_, v)| v)
+    }
 }
 
-#[allow(unused_qualifications)]
-impl ::rustc_serialize::Decodable for MedianValue {
-    fn decode<__D: ::rustc_serialize::Decoder>(__arg_0: &mut __D) -> ::std::result::Result<MedianValue, __D::Error> {
-        __arg_0.read_struct("MedianValue", 1usize, |_d| -> _ {
-            ::std::result::Result::Ok(MedianValue{value:
-               match _d.read_struct_field("50.0", 0usize, ::rustc_serialize::Decodable::decode)
-               {
-                   ::std::result::Result::Ok(__try_var) => __try_var,
-                   ::std::result::Result::Err(__try_var) => return ::std::result::Result::Err(__try_var),
-               }
-           ,})
-       })
-   }
+impl Decodable for PercentileMap {
+    fn decode<D: Decoder>(d: &mut D) -> Result<PercentileMap, D::Error> {
+        d.read_map(|d, len| {
+            let mut values = Vec::with_capacity(len);
+            for i in 0..len {
+                let key: String = try!(d.read_map_elt_key(i, Decodable::decode));
+                let value: Option<f64> = try!(d.read_map_elt_val(i, Decodable::decode));
+                if let (Ok(percent), Some(value)) = (key.parse(), value) {
+                    values.push((percent, value));
+                }
+            }
+            Ok(PercentileMap {
+                values: values
+            })
+        })
+    }
 }
 
 #[derive(RustcDecodable, Debug)]
@@ -113,74 +140,190 @@ pub struct StdDeviationBounds {
 }
 
 #[derive(RustcEncodable, RustcDecodable, Debug)]
-pub struct HotCloudQuery {
-    pub template: SearchTemplate,
-    pub params: SearchParams
+pub struct SearchParams {
+    pub start: String,
+    pub end: String
 }
 
-impl HotCloudQuery {
-    pub fn new(hour: usize) -> HotCloudQuery {
-        HotCloudQuery {
-            template: SearchTemplate::new(),
-            params: SearchParams::new(hour)
+impl SearchParams {
+    /// The time range ending at `hour`, reaching `lookback` hours into the past
+    pub fn new(hour: usize, lookback: usize) -> SearchParams {
+        let start = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64) - Duration::hours(lookback as i64);
+        let end = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64);
+
+        SearchParams {
+            start: start.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            end: end.format("%Y-%m-%dT%H:%M:%S").to_string(),
         }
     }
+
+    /// The mustache placeholders a search template takes its time range from
+    pub fn placeholders() -> SearchParams {
+        SearchParams {
+            start: "{{start}}".to_owned(),
+            end: "{{end}}".to_owned()
+        }
+    }
+
+    /// Restrict a search to this time range
+    pub fn filter(&self) -> Filter {
+        Filter::Range {
+            field: "hour",
+            gte: self.start.clone(),
+            lte: self.end.clone()
+        }
+    }
+
+    /// This time range as the params of a search template
+    pub fn to_params(&self) -> Vec<(&'static str, Json)> {
+        vec![
+            ("start", Json::String(self.start.clone())),
+            ("end", Json::String(self.end.clone()))
+        ]
+    }
 }
 
-#[derive(RustcEncodable, RustcDecodable, Debug)]
-pub struct SearchTemplate {
-    pub id: String
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct HotcloudResult {
+    pub metric: usize,
+    pub hour: String,
+    /// The headline statistic, i.e. the first of `detect.percentiles`
+    pub value: f64,
+    /// Every requested statistic of the per-query surprises (p90, p99_9, max, mean...)
+    pub stats: BTreeMap<String, f64>,
+    /// The queries with the largest surprise this hour, and the nodes behind them
+    pub top: Vec<Contributor>
+}
+
+impl Document for HotcloudResult {
+    fn id(&self) -> String {
+        format!("{}_{}", self.hour, self.metric)
+    }
 }
 
-impl SearchTemplate {
-    pub fn new() -> SearchTemplate {
-        SearchTemplate {
-            id: "hotcloud".to_owned()
+/// The hotcloud search template in the cluster's dialect: the hourly surprise of every
+/// (metric,query) series against the configured baseline model and scorer, summarised
+/// per metric by the configured percentiles and statistics.  A non-empty `es.query`
+/// replaces it wholesale
+pub fn template(config: &Config, dialect: Dialect) -> Json {
+    if config.es.query.len() > 0 {
+        // A template with unquoted tags (`"size": {{size}}`) is only JSON once rendered,
+        // so it's stored as mustache source text
+        return match Json::from_str(&config.es.query) {
+            Ok(query) => upgrade_query(query),
+            Err(_) => Json::String(config.es.query.clone())
+        };
+    }
+
+    let (model, scorer) = detectors(config);
+    let mut aggs = vec![
+        ("queries", Agg::Terms { field: "query", size: config.queries, aggs: vec![
+            ("series", series_agg(config, &model, &scorer)),
+            ("largest_surprise", Agg::MaxBucket("series.surprise"))
+        ]}),
+        ("surprise_percentiles", Agg::PercentilesBucket {
+            path: "queries>largest_surprise",
+            percents: config.detect.percentiles.clone()
+        })
+    ];
+
+    for stat in &config.detect.stats {
+        match &**stat {
+            "max" => aggs.push(("max_surprise", Agg::MaxBucket("queries>largest_surprise"))),
+            _ => aggs.push(("mean_surprise", Agg::AvgBucket("queries>largest_surprise")))
         }
     }
+
+    Search {
+        filters: vec![SearchParams::placeholders().filter()],
+        aggs: vec![("metrics", Agg::Terms { field: "metric", size: config.metrics, aggs: aggs })]
+    }.to_json(dialect)
 }
 
-#[derive(RustcEncodable, RustcDecodable, Debug)]
-pub struct SearchParams {
-    pub start: String,
-    pub end: String
+// An `es.query` written for earlier versions of hotcloud: still wrapped in the
+// `{"template": ...}` of the 2.x API, which `Cluster::put_template` now adds itself, and
+// with its percentiles named `ninetieth_surprise`
+fn upgrade_query(query: Json) -> Json {
+    let unwrapped = query.as_object().and_then(|fields| match fields.len() {
+        1 => fields.get("template").cloned(),
+        _ => None
+    });
+
+    match unwrapped {
+        Some(source) => {
+            info!("es.query is in the old format, unwrapping it from its \"template\" and renaming ninetieth_surprise to surprise_percentiles");
+            rename(source, "ninetieth_surprise", "surprise_percentiles")
+        },
+        None => query
+    }
 }
 
-impl SearchParams {
-    pub fn new(hour: usize) -> SearchParams {
-        let start = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64) - Duration::days(1);
-        let end = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64);
+// Rename every field called `from` in a JSON document
+fn rename(json: Json, from: &str, to: &str) -> Json {
+    match json {
+        Json::Object(fields) => Json::Object(fields.into_iter().map(|(name, value)| {
+            (if name == from { to.to_owned() } else { name }, rename(value, from, to))
+        }).collect()),
+        Json::Array(values) => Json::Array(values.into_iter().map(|value| rename(value, from, to)).collect()),
+        other => other
+    }
+}
 
-        SearchParams {
-            start: start.format("%Y-%m-%dT%H:%M:%S").to_string(),
-            end: end.format("%Y-%m-%dT%H:%M:%S").to_string(),
+/// The hourly averages of a series, with the baseline and surprise of every hour.
+/// Scorers without a script never run through a template, so they get the `abs` one
+pub fn series_agg(config: &Config, model: &Model, scorer: &Scorer) -> Agg {
+    let script = scorer.script().or(Scorer::Absolute.script()).unwrap();
+    Agg::Hourly { field: "hour", aggs: vec![
+        ("avg", Agg::Avg("value")),
+        ("movavg", Agg::MovingAvg { path: "avg", window: config.detect.window, model: model.clone() }),
+        ("surprise", Agg::BucketScript { paths: vec![("avg", "avg"), ("movavg", "movavg")], script: script })
+    ]}
+}
+
+/// The baseline model and surprise scorer from the `[detect]` section
+pub fn detectors(config: &Config) -> (Model, Scorer) {
+    let model = Model::new(&config.detect).unwrap_or_else(|err| panic!("Invalid [detect] config: {}", err));
+    let scorer = Scorer::new(&config.detect.scorer).unwrap_or_else(|err| panic!("Invalid [detect] config: {}", err));
+
+    if config.detect.percentiles.len() == 0 {
+        panic!("Invalid [detect] config: percentiles must list at least one percentile");
+    }
+    for stat in &config.detect.stats {
+        if stat != "max" && stat != "mean" {
+            panic!("Invalid [detect] config: unknown stat [{}], expected max or mean", stat);
         }
     }
-}
 
-#[derive(RustcDecodable, RustcEncodable)]
-struct HotcloudResult {
-    metric: usize,
-    hour: String,
-    value: f64
+    if !config.detect.native && scorer.script().is_none() {
+        info!("The {:?} scorer has no search template form, detecting natively", scorer);
+    }
+
+    (model, scorer)
 }
 
-// Run the query portion of Atlas, to pre-cache the values (which simplifies the demo)
-pub fn run_hotcloud(client: &Arc<Client>, config: Config) {
+// Run the query portion of Atlas, to pre-cache the values (which simplifies the demo).
+// Each thread saves how far it has got to the checkpoint, and carries on from there
+pub fn run_hotcloud(es: &Arc<Cluster>, config: Config, reporter: &Reporter, checkpoint: &Arc<Checkpoint>, shutdown: &Shutdown) {
     let config = Arc::new(config);
 
     // We can query in parallel to speed up the process, divide the timeline
-    // by number of threads
-    let mut guards = Vec::with_capacity(config.threads);
-    let batch_size: usize = config.hours / config.threads;
+    // by number of threads.  A resumed run keeps the batches it started with
+    let mut batches = checkpoint.progress().batches;
+    if batches.len() == 0 {
+        // The last batch also takes the hours left over by the division
+        let batch_size: usize = config.hours / config.threads;
+        batches = (0..config.threads).map(|i| Batch { next: batch_size * i, end: batch_size * (i + 1) }).collect();
+        batches[config.threads - 1].end = config.hours;
+        checkpoint.update(|progress| progress.batches = batches.clone());
+    }
 
-    for i in 0..config.threads {
-        let start = batch_size * i;
-        let end = start + batch_size;
+    let mut guards = Vec::with_capacity(batches.len());
+    for (i, batch) in batches.into_iter().enumerate() {
         let config_clone = config.clone();
-        let client_clone = client.clone();
+        let (es_clone, reporter_clone, checkpoint_clone) = (es.clone(), reporter.clone(), checkpoint.clone());
+        let shutdown_clone = shutdown.clone();
         guards.push(thread::spawn(move ||{
-            query_thread(start, end, client_clone, config_clone);
+            query_thread(i, batch, es_clone, config_clone, reporter_clone, checkpoint_clone, shutdown_clone);
         }));
     }
 
@@ -190,62 +333,307 @@ pub fn run_hotcloud(client: &Arc<Client>, config: Config) {
 
 }
 
-fn query_thread(start: usize, end: usize, client: Arc<Client>, config: Arc<Config>) {
+fn query_thread(index: usize, batch: Batch, es: Arc<Cluster>, config: Arc<Config>, reporter: Reporter, checkpoint: Arc<Checkpoint>,
+                shutdown: Shutdown) {
     let mut bulk: Vec<HotcloudResult> = Vec::with_capacity(config.es.bulk_size);
-    debug!("Running Hotcloud Queries ({} to {})...", start, end);
-
-    let batch_size: usize = config.hours / config.threads;
-
-    let mut c = 0;
-    for hour in start..end {
+    let mut nodes: Vec<NodeResult> = Vec::new();
+    debug!("Running Hotcloud Queries ({} to {})...", batch.next, batch.end);
 
-        debug!("{}", hour);
-        let body = HotCloudQuery::new(hour);
-        let body = json::encode(&body).unwrap();
-
-        // Execute a query using the pre-saved search template and extensive
-        // filter_path filtering
-        let mut hyper_response = client.post("http://localhost:9200/data/data/_search/template?filter_path=aggregations.**.ninetieth_surprise,aggregations.metrics.buckets.key")
-              .body(&body)
-              .send().unwrap();
-
-        let mut body = String::new();
-        let _ = match hyper_response.status {
-            StatusCode::Ok => {hyper_response.read_to_string(&mut body)},
-            _ => continue
-        };
+    let (model, scorer) = detectors(&config);
 
-        let decoded: Response = json::decode(&body).unwrap();
+    let batch_size: usize = batch.end - batch.next;
 
-        if decoded.aggregations.metrics.buckets.len() == 0 {
-            continue;
+    let mut c = 0;
+    let mut next = batch.next;
+    let mut saved = batch.next;
+    for hour in batch.next..batch.end {
+        if shutdown.requested() {
+            break;
         }
 
-        // Extract the five 90th percentile surprise values and re-index into ES
-        for metric in decoded.aggregations.metrics.buckets {
-            bulk.push(HotcloudResult {
-                metric: metric.key,
-                hour: (UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64)).format("%Y-%m-%dT%H:%M:%S").to_string(),
-                value: metric.ninetieth_surprise.values.value
-            });
+        // An hour that can't be scored stops the batch there, so the checkpoint never
+        // gets past it and a resumed run starts with it
+        debug!("{}", hour);
+        match score_hour_retrying(&es, &config, &model, &scorer, hour, &shutdown) {
+            Ok((results, node_results)) => {
+                bulk.extend(report(&reporter, hour, results));
+                nodes.extend(node_results);
+            },
+            Err(err) => {
+                error!("Could not score hour {}, leaving hours {} to {} unscored: {}", hour, hour, batch.end - 1, err);
+                break;
+            }
         }
 
-        if bulk.len() >= 500 {
+        // Both kinds of results are sent together, so every hour before the next one
+        // saved to the checkpoint is fully indexed.  A bulk that was given up on stops
+        // the batch where it was last saved, to be scored again on resume
+        if bulk.len() >= 500 || nodes.len() >= 500 {
             debug!("{}%", (c as f32 / batch_size as f32)*100f32);
 
-            while ::ACTIVE_THREADS.load(Ordering::SeqCst) >= config.threads {
+            while es.in_flight.load(Ordering::SeqCst) >= config.threads {
                 thread::sleep_ms(500);
             }
             debug!(".");
-            ::util::send_bulk("http://localhost:9200/hotcloud/data/_bulk", &client, bulk);
+            let sent = send_scored(&es, bulk, nodes);
             bulk = Vec::with_capacity(config.es.bulk_size);
+            nodes = Vec::new();
+            if let Err(err) = sent {
+                error!("Could not index hours {} to {}, leaving hours {} to {} unscored: {}", saved, hour, saved, batch.end - 1, err);
+                next = saved;
+                break;
+            }
+            saved = hour + 1;
+            checkpoint.update(|progress| progress.batches[index].next = hour + 1);
         }
 
         c += 1;
+        next = hour + 1;
     }
 
-    ::util::send_bulk("http://localhost:9200/hotcloud/data/_bulk", &client, bulk);
+    match send_scored(&es, bulk, nodes) {
+        Ok(()) => checkpoint.update(|progress| progress.batches[index].next = next),
+        Err(err) => error!("Could not index hours {} to {}, leaving hours {} to {} unscored: {}", saved, next - 1, saved, batch.end - 1, err)
+    }
 
     // manual refresh
-    let _ = client.put("http://localhost:9200/hotcloud/_refresh").send();
+    es.refresh(&[Doc::Hotcloud, Doc::Node]);
+}
+
+// Index the hotcloud and node results of some hours, failing if either bulk was given up on
+fn send_scored(es: &Cluster, bulk: Vec<HotcloudResult>, nodes: Vec<NodeResult>) -> Result<(), String> {
+    try!(::util::index_bulk(es, Doc::Hotcloud, bulk));
+    ::util::index_bulk(es, Doc::Node, nodes)
+}
+
+// Keep the hotcloud up to date with a live data index: remember the last hour we
+// scored, poll for newly indexed data, and score each hour once it is complete.  Runs
+// until a shutdown is requested
+pub fn follow_hotcloud(es: &Arc<Cluster>, config: &Config, reporter: &Reporter, shutdown: &Shutdown) {
+    let (model, scorer) = detectors(config);
+    let mut changepoints = match config.changepoint.enabled {
+        true => Some(ChangePoints::new(&config.changepoint)
+                        .unwrap_or_else(|err| panic!("Invalid [changepoint] config: {}", err))),
+        false => None
+    };
+
+    // Pick up where a previous run left off, otherwise start with the oldest data
+    let mut next = match latest_hour(es, Doc::Hotcloud) {
+        Some(hour) => hour + 1,
+        None => {
+            let mut earliest = None;
+            while earliest.is_none() {
+                if !shutdown.sleep_ms(config.follow.poll as u32 * 1000) {
+                    return;
+                }
+                earliest = earliest_hour(es, Doc::Data);
+            }
+            earliest.unwrap()
+        }
+    };
+
+    debug!("Following hotcloud from hour {}...", next);
+    loop {
+        es.refresh(&[Doc::Data]);
+
+        // The newest hour may still be arriving, so only hours before it are complete
+        if let Some(latest) = latest_hour(es, Doc::Data) {
+            let (mut bulk, mut nodes, mut changes) = (Vec::new(), Vec::new(), Vec::new());
+            let (first, before) = (next, changepoints.clone());
+            while next < latest && !shutdown.requested() {
+                debug!("{}", next);
+
+                // A failed hour isn't skipped: it's the first one tried on the next poll
+                let (results, node_results) = match score_hour(es, config, &model, &scorer, next) {
+                    Ok(scored) => scored,
+                    Err(err) => {
+                        warn!("Could not score hour {}, trying again in {}s: {}", next, config.follow.poll, err);
+                        break;
+                    }
+                };
+                let averages = match changepoints {
+                    Some(_) => match changepoint::hour_averages(es, config, next) {
+                        Ok(averages) => Some(averages),
+                        Err(err) => {
+                            warn!("Could not score hour {}, trying again in {}s: {}", next, config.follow.poll, err);
+                            break;
+                        }
+                    },
+                    None => None
+                };
+                bulk.extend(report(reporter, next, results));
+                nodes.extend(node_results);
+                if let (Some(changepoints), Some(averages)) = (changepoints.as_mut(), averages) {
+                    changes.extend(changepoints.update(next, averages));
+                }
+                next += 1;
+            }
+
+            // Like a failed search, a bulk that was given up on sends the poll's hours
+            // back to be scored again, with the change points as they were before them
+            if bulk.len() > 0 || nodes.len() > 0 || changes.len() > 0 {
+                let sent = send_scored(es, bulk, nodes).and_then(|_| ::util::index_bulk(es, Doc::ChangePoint, changes));
+                if let Err(err) = sent {
+                    warn!("Could not index hours {} to {}, trying again in {}s: {}", first, next - 1, config.follow.poll, err);
+                    next = first;
+                    changepoints = before;
+                }
+                es.refresh(&[Doc::Hotcloud, Doc::Node, Doc::ChangePoint]);
+            }
+        }
+
+        if !shutdown.sleep_ms(config.follow.poll as u32 * 1000) {
+            return;
+        }
+    }
+}
+
+// Pass the hotcloud values of an hour on to the dashboard
+fn report(reporter: &Reporter, hour: usize, results: Vec<HotcloudResult>) -> Vec<HotcloudResult> {
+    for result in &results {
+        reporter.send(Event::Scored {
+            metric: result.metric,
+            hour: hour,
+            value: result.value
+        });
+    }
+    results
+}
+
+// Score an hour, trying again with backoff while its searches fail
+fn score_hour_retrying(es: &Arc<Cluster>, config: &Config, model: &Model, scorer: &Scorer, hour: usize,
+                       shutdown: &Shutdown) -> Result<(Vec<HotcloudResult>, Vec<NodeResult>), String> {
+    let mut attempt = 0;
+    loop {
+        match score_hour(es, config, model, scorer, hour) {
+            Err(ref err) if attempt < RETRIES => {
+                warn!("Could not score hour {}, trying again: {}", hour, err);
+                if !shutdown.sleep_ms(250 << attempt) {
+                    return Err("stopped while retrying".to_owned());
+                }
+                attempt += 1;
+            },
+            result => return result
+        }
+    }
+}
+
+// Score a single hour: the hotcloud of every metric, and of every node if node.enabled.
+// Fails if any of the searches does, rather than passing the hour off as quiet
+fn score_hour(es: &Arc<Cluster>, config: &Config, model: &Model, scorer: &Scorer, hour: usize)
+              -> Result<(Vec<HotcloudResult>, Vec<NodeResult>), String> {
+    let start = PreciseTime::now();
+    let results = try!(query_hour(es, config, model, scorer, hour));
+    let nodes = match config.node.enabled {
+        true => try!(node::query_hour(es, config, model, scorer, hour)),
+        false => vec![]
+    };
+    es.stats.query(start, PreciseTime::now());
+    Ok((results, nodes))
+}
+
+// Compute the hotcloud values (one per metric) for a single hour
+fn query_hour(es: &Arc<Cluster>, config: &Config, model: &Model, scorer: &Scorer, hour: usize) -> Result<Vec<HotcloudResult>, String> {
+    if config.detect.native || scorer.script().is_none() {
+        let mut results = try!(::detect::query_hour(es, config, model, scorer, hour));
+        try!(attribution::attribute_nodes(es, config, model, scorer, hour, &mut results));
+        return Ok(results);
+    }
+
+    // Execute a query using the pre-saved search template and extensive
+    // filter_path filtering.  The per-query surprises are only kept when we
+    // need them for attribution
+    let mut filter_path = "aggregations.metrics.buckets.key,aggregations.**.surprise_percentiles,aggregations.**.max_surprise,aggregations.**.mean_surprise".to_owned();
+    if config.attribution.queries > 0 {
+        filter_path.push_str(",aggregations.metrics.buckets.queries.buckets.key,aggregations.metrics.buckets.queries.buckets.largest_surprise.value");
+    }
+
+    let params = SearchParams::new(hour, config.detect.window).to_params();
+    let body = match es.search_template(Doc::Data, "hotcloud", params, &filter_path) {
+        Some(body) => body,
+        None => return Err("the hotcloud search template failed".to_owned())
+    };
+
+    let decoded: Response = match try!(decode_aggregations(&body)) {
+        Some(decoded) => decoded,
+        None => return Ok(vec![])
+    };
+
+    // Extract the five 90th percentile surprise values to be re-indexed into ES
+    let mut results: Vec<HotcloudResult> = decoded.aggregations.metrics.buckets.into_iter().filter_map(|metric| {
+        let key = metric.key;
+        let mut stats = BTreeMap::new();
+        for percent in &config.detect.percentiles {
+            if let Some(value) = metric.surprise_percentiles.values.get(*percent) {
+                stats.insert(::detect::stat_name(*percent), value);
+            }
+        }
+        if let Some(value) = metric.max_surprise.and_then(|m| m.value) {
+            stats.insert("max".to_owned(), value);
+        }
+        if let Some(value) = metric.mean_surprise.and_then(|m| m.value) {
+            stats.insert("mean".to_owned(), value);
+        }
+
+        let surprises = metric.queries.map_or(vec![], |queries| {
+            queries.buckets.into_iter().filter_map(|q| q.largest_surprise.value.map(|v| (q.key, v))).collect()
+        });
+
+        let headline = stats.get(&::detect::stat_name(config.detect.percentiles[0])).cloned();
+        headline.map(|value| {
+            HotcloudResult {
+                metric: key,
+                hour: (UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64)).format("%Y-%m-%dT%H:%M:%S").to_string(),
+                value: value,
+                stats: stats,
+                top: attribution::top_queries(surprises, config.attribution.queries)
+            }
+        })
+    }).collect();
+
+    try!(attribution::attribute_nodes(es, config, model, scorer, hour, &mut results));
+    Ok(results)
+}
+
+/// Decode the response of a search run with a `filter_path`, which leaves out the
+/// `aggregations` altogether when they have no buckets: that's None, not an error
+pub fn decode_aggregations<T: Decodable>(body: &str) -> Result<Option<T>, String> {
+    let response = try!(Json::from_str(body).map_err(|err| format!("could not parse the response: {}", err)));
+    if response.find("aggregations").is_none() {
+        return Ok(None);
+    }
+
+    T::decode(&mut json::Decoder::new(response)).map(Some).map_err(|err| format!("could not decode the response: {}", err))
+}
+
+fn latest_hour(es: &Arc<Cluster>, doc: Doc) -> Option<usize> {
+    hour_bound(es, doc, Agg::Max("hour"))
+}
+
+fn earliest_hour(es: &Arc<Cluster>, doc: Doc) -> Option<usize> {
+    hour_bound(es, doc, Agg::Min("hour"))
+}
+
+// Find the min/max `hour` of a kind of document, as an hour offset from the start of the timeline
+fn hour_bound(es: &Arc<Cluster>, doc: Doc, agg: Agg) -> Option<usize> {
+    let search = Search {
+        filters: vec![],
+        aggs: vec![("bound", agg)]
+    };
+
+    let body = match es.search(doc, &search, "aggregations.bound.value") {
+        Some(body) => body,
+        None => return None
+    };
+
+    // An empty index has a null bound (or no aggregations at all with filter_path)
+    let decoded: BoundResponse = match json::decode(&body) {
+        Ok(decoded) => decoded,
+        Err(_) => return None
+    };
+
+    decoded.aggregations.bound.value.map(|millis| {
+        let epoch = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0).timestamp();
+        ((millis as i64 / 1000 - epoch) / 3600) as usize
+    })
 }
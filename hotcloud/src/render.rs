@@ -0,0 +1,310 @@
+use config::Config;
+use detect;
+use es::{Agg, Cluster, Doc, Search};
+use query::OptionalValue;
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use std::cmp;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use chrono::{Duration, NaiveDateTime, UTC};
+use chrono::offset::TimeZone;
+
+// Room for the metric labels on the left, and the hour axis and legend below
+const LABEL_WIDTH: usize = 80;
+const AXIS_HEIGHT: usize = 40;
+const LEGEND_HEIGHT: usize = 30;
+const LEGEND_WIDTH: usize = 620;
+
+// Heat scale of the cells, from no surprise to the top of the scale
+const SCALE: [(f64, f64, f64); 3] = [(255.0, 255.0, 204.0), (253.0, 141.0, 60.0), (189.0, 0.0, 38.0)];
+
+// Outline colour of each kind of disruption (node, query, metric)
+const TRUTH: [&'static str; 3] = ["#2166ac", "#1a9850", "#762a83"];
+
+#[derive(RustcDecodable, Debug)]
+struct HotcloudDoc {
+    metric: usize,
+    hour: String,
+    value: f64
+}
+
+#[derive(RustcDecodable, Debug)]
+struct DataDoc {
+    metric: usize,
+    hour: String,
+    hit: usize
+}
+
+#[derive(RustcDecodable, Debug)]
+struct HeatResponse {
+    aggregations: HeatAggs
+}
+
+#[derive(RustcDecodable, Debug)]
+struct HeatAggs {
+    metrics: HeatMetrics
+}
+
+#[derive(RustcDecodable, Debug)]
+struct HeatMetrics {
+    buckets: Vec<HeatMetricBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+struct HeatMetricBucket {
+    key: usize,
+    series: HeatSeries
+}
+
+#[derive(RustcDecodable, Debug)]
+struct HeatSeries {
+    buckets: Vec<HeatHourBucket>
+}
+
+#[derive(RustcDecodable, Debug)]
+struct HeatHourBucket {
+    key: f64,
+    max: OptionalValue
+}
+
+/// The hotcloud value and the disruption ground truth of every metric/hour, keyed by
+/// (metric, hour)
+pub struct Heatmap {
+    pub values: BTreeMap<(usize, usize), f64>,
+    pub truth: BTreeMap<(usize, usize), usize>
+}
+
+// Render the hotcloud into a self-contained heatmap, reading the hotcloud and the
+// ground truth from ES unless local files are configured
+pub fn render(config: &Config) {
+    let es = match config.render.hotcloud.len() == 0 || config.render.data.len() == 0 {
+        true => Some(Cluster::connect(&config.es.url).unwrap_or_else(|err| panic!("{}", err))),
+        false => None
+    };
+
+    let values = match es {
+        Some(ref es) if config.render.hotcloud.len() == 0 => fetch_max(es, config, Doc::Hotcloud, "value"),
+        _ => read_hotcloud(&config.render.hotcloud)
+    };
+    let truth = match es {
+        Some(ref es) if config.render.data.len() == 0 => {
+            fetch_max(es, config, Doc::Data, "hit").into_iter().map(|(k, v)| (k, v as usize)).collect()
+        },
+        _ => read_truth(&config.render.data)
+    };
+
+    let heatmap = Heatmap {
+        values: values,
+        truth: truth
+    };
+    if heatmap.values.len() == 0 {
+        error!("No hotcloud values to render");
+        return;
+    }
+
+    let svg = heatmap.to_svg(config.render.cell_width, config.render.cell_height);
+    let output = match config.render.output.ends_with(".svg") {
+        true => svg,
+        false => format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>hotcloud</title>\n\
+                          <style>body {{ font-family: sans-serif; }}</style>\n</head>\n<body>\n\
+                          <h1>hotcloud</h1>\n{}\n</body>\n</html>\n", svg)
+    };
+
+    let mut file = File::create(&config.render.output)
+        .unwrap_or_else(|err| panic!("Error while creating {}: [{}]", config.render.output, err));
+    file.write_all(output.as_bytes())
+        .unwrap_or_else(|err| panic!("Error while writing {}: [{}]", config.render.output, err));
+    info!("Rendered {} metric/hours to {}", heatmap.values.len(), config.render.output);
+}
+
+// The max of `field` per metric/hour.  Every metric/hour holds exactly one hotcloud
+// value, while the ground truth is the worst disruption of any of its tuples
+fn fetch_max(es: &Cluster, config: &Config, doc: Doc, field: &'static str) -> BTreeMap<(usize, usize), f64> {
+    let search = Search {
+        filters: vec![],
+        aggs: vec![("metrics", Agg::Terms { field: "metric", size: config.metrics, aggs: vec![
+            ("series", Agg::Hourly { field: "hour", aggs: vec![("max", Agg::Max(field))] })
+        ]})]
+    };
+
+    let response: HeatResponse = match es.search(doc, &search, "").and_then(|body| json::decode(&body).ok()) {
+        Some(response) => response,
+        None => {
+            error!("Error while fetching {} from {}", field, es.index(doc));
+            return BTreeMap::new();
+        }
+    };
+
+    let epoch = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0).timestamp();
+    let mut values = BTreeMap::new();
+    for metric in response.aggregations.metrics.buckets {
+        for bucket in metric.series.buckets {
+            if let Some(value) = bucket.max.value {
+                values.insert((metric.key, ((bucket.key as i64 / 1000 - epoch) / 3600) as usize), value);
+            }
+        }
+    }
+    values
+}
+
+// Hotcloud documents, one JSON object per line (a `_bulk` body or an export of the
+// `hotcloud` index).  Lines that aren't hotcloud documents are skipped
+fn read_hotcloud(path: &str) -> BTreeMap<(usize, usize), f64> {
+    let mut values = BTreeMap::new();
+    for line in read_lines(path) {
+        if let Ok(doc) = json::decode::<HotcloudDoc>(&line) {
+            if let Some(hour) = to_hour(&doc.hour) {
+                values.insert((doc.metric, hour), doc.value);
+            }
+        }
+    }
+    values
+}
+
+// The generator's JSON output, reduced to the worst disruption per metric/hour.  Only
+// the tuples a disruption hit have a `hit`, so a metric disruption marks its metrics alone
+fn read_truth(path: &str) -> BTreeMap<(usize, usize), usize> {
+    let mut truth = BTreeMap::new();
+    for line in read_lines(path) {
+        if let Ok(doc) = json::decode::<DataDoc>(&line) {
+            if let Some(hour) = to_hour(&doc.hour) {
+                let worst = truth.entry((doc.metric, hour)).or_insert(0);
+                if doc.hit > *worst {
+                    *worst = doc.hit;
+                }
+            }
+        }
+    }
+    truth
+}
+
+fn read_lines(path: &str) -> Vec<String> {
+    let file = File::open(path).unwrap_or_else(|err| panic!("Error while opening {}: [{}]", path, err));
+    BufReader::new(file).lines().filter_map(|line| line.ok()).collect()
+}
+
+// The hour offset of a timestamp from the start of the timeline
+fn to_hour(timestamp: &str) -> Option<usize> {
+    let start = UTC.ymd(2015, 1, 1).and_hms(0, 0, 0).naive_utc();
+    NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S").ok().and_then(|hour| {
+        match (hour - start).num_hours() {
+            h if h >= 0 => Some(h as usize),
+            _ => None
+        }
+    })
+}
+
+impl Heatmap {
+
+    /// Render as an SVG: one row per metric, one column per hour, each cell coloured by
+    /// its hotcloud value and every disruption outlined on top of the metric it hit
+    pub fn to_svg(&self, cell_width: usize, cell_height: usize) -> String {
+        let metrics: Vec<usize> = {
+            let mut metrics: Vec<usize> = self.values.keys().map(|&(m, _)| m).collect();
+            metrics.dedup();
+            metrics
+        };
+        let first = self.values.keys().map(|&(_, h)| h).min().unwrap();
+        let last = self.values.keys().map(|&(_, h)| h).max().unwrap();
+        let hours = last - first + 1;
+
+        // The top of the scale is the 99th percentile, so a few outliers don't wash out
+        // the rest of the cloud
+        let values: Vec<f64> = self.values.values().cloned().collect();
+        let top = match detect::percentile(&values, 99.0).unwrap() {
+            top if top > 0.0 => top,
+            _ => 1.0
+        };
+
+        let width = cmp::max(LABEL_WIDTH + hours * cell_width, LEGEND_WIDTH);
+        let height = metrics.len() * cell_height + AXIS_HEIGHT + LEGEND_HEIGHT;
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+                               font-family=\"sans-serif\" font-size=\"11\">\n", width, height);
+
+        for (row, metric) in metrics.iter().enumerate() {
+            let y = row * cell_height;
+            svg.push_str(&format!("<text x=\"{}\" y=\"{}\" text-anchor=\"end\" dominant-baseline=\"middle\">metric {}</text>\n",
+                                  LABEL_WIDTH - 6, y + cell_height / 2, metric));
+
+            for hour in first..last + 1 {
+                let x = LABEL_WIDTH + (hour - first) * cell_width;
+                let (fill, title) = match self.values.get(&(*metric, hour)) {
+                    Some(value) => (heat(value / top), format!("metric {}, {}: {:.3}", metric, timestamp(hour), value)),
+                    None => ("#eeeeee".to_owned(), format!("metric {}, {}: no value", metric, timestamp(hour)))
+                };
+                svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"><title>{}</title></rect>\n",
+                                      x, y, cell_width, cell_height, fill, title));
+            }
+
+            // Outline each run of consecutive hours under the same kind of disruption
+            let mut hour = first;
+            while hour <= last {
+                let kind = self.truth.get(&(*metric, hour)).cloned().unwrap_or(0);
+                let mut end = hour + 1;
+                while end <= last && self.truth.get(&(*metric, end)).cloned().unwrap_or(0) == kind {
+                    end += 1;
+                }
+
+                if kind > 0 {
+                    svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"2\">\
+                                           <title>{} disruption, {} to {}</title></rect>\n",
+                                          LABEL_WIDTH + (hour - first) * cell_width, y + 1, (end - hour) * cell_width, cell_height - 2,
+                                          TRUTH[(kind - 1) % 3], disruption_name(kind), timestamp(hour), timestamp(end - 1)));
+                }
+                hour = end;
+            }
+        }
+
+        // A tick per day, thinned out so the labels don't overlap
+        let axis = metrics.len() * cell_height;
+        let days = (60 + 24 * cell_width - 1) / (24 * cell_width);
+        let mut hour = first + (24 - first % 24) % 24;
+        while hour <= last {
+            let x = LABEL_WIDTH + (hour - first) * cell_width;
+            svg.push_str(&format!("<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"#333333\"/>\n", x, axis, x, axis + 5));
+            svg.push_str(&format!("<text x=\"{}\" y=\"{}\">{}</text>\n", x + 2, axis + 16, &timestamp(hour)[..10]));
+            hour += 24 * days;
+        }
+
+        let legend = axis + AXIS_HEIGHT;
+        svg.push_str(&format!("<text x=\"0\" y=\"{}\">surprise: 0</text>\n", legend + 12));
+        for i in 0..10 {
+            svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"10\" height=\"14\" fill=\"{}\"/>\n",
+                                  70 + i * 10, legend + 1, heat(i as f64 / 9.0)));
+        }
+        svg.push_str(&format!("<text x=\"176\" y=\"{}\">{:.3}+</text>\n", legend + 12, top));
+        for kind in 1..4 {
+            let x = 260 + (kind - 1) * 110;
+            svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"14\" height=\"14\" fill=\"none\" stroke=\"{}\" stroke-width=\"2\"/>\n",
+                                  x, legend + 1, TRUTH[kind - 1]));
+            svg.push_str(&format!("<text x=\"{}\" y=\"{}\">{} disruption</text>\n", x + 20, legend + 12, disruption_name(kind)));
+        }
+
+        svg.push_str("</svg>");
+        svg
+    }
+}
+
+// The colour of a cell, with `t` the fraction of the way up the scale
+fn heat(t: f64) -> String {
+    let t = if t.is_finite() { t.max(0.0).min(1.0) } else { 1.0 };
+    let (lo, hi, t) = match t < 0.5 {
+        true => (SCALE[0], SCALE[1], t * 2.0),
+        false => (SCALE[1], SCALE[2], (t - 0.5) * 2.0)
+    };
+    format!("#{:02x}{:02x}{:02x}",
+            (lo.0 + (hi.0 - lo.0) * t) as u8, (lo.1 + (hi.1 - lo.1) * t) as u8, (lo.2 + (hi.2 - lo.2) * t) as u8)
+}
+
+fn disruption_name(kind: usize) -> &'static str {
+    match kind {
+        1 => "node",
+        2 => "query",
+        _ => "metric"
+    }
+}
+
+fn timestamp(hour: usize) -> String {
+    (UTC.ymd(2015, 1, 1).and_hms(0, 0, 0) + Duration::hours(hour as i64)).format("%Y-%m-%dT%H:%M:%S").to_string()
+}
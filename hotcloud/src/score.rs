@@ -0,0 +1,86 @@
+use detect::percentile;
+use es::Script;
+
+// Scale factor that makes the MAD a consistent estimator of the standard deviation
+const MAD_SCALE: f64 = 1.4826;
+
+/// Ways of turning an hourly average and its baseline into a "surprise" value.  Only
+/// `Absolute` depends on the scale of the metric, the rest are normalised so surprise
+/// is comparable between metrics.
+#[derive(Debug, Clone)]
+pub enum Scorer {
+    /// |avg - baseline|
+    Absolute,
+    /// |avg - baseline| in standard deviations of the window
+    ZScore,
+    /// |avg - median| in (scaled) median absolute deviations of the window
+    Mad,
+    /// |avg - baseline| as a percentage of the baseline
+    Percent,
+    /// |ln(avg / baseline)|
+    LogRatio
+}
+
+impl Scorer {
+    pub fn new(name: &str) -> Result<Scorer, String> {
+        match name {
+            "abs" => Ok(Scorer::Absolute),
+            "zscore" => Ok(Scorer::ZScore),
+            "mad" => Ok(Scorer::Mad),
+            "percent" => Ok(Scorer::Percent),
+            "log_ratio" => Ok(Scorer::LogRatio),
+            other => Err(format!("Unknown scorer [{}], expected abs, zscore, mad, percent or log_ratio", other))
+        }
+    }
+
+    /// The `bucket_script` equivalent for the search template.  Scorers that need the
+    /// whole window of values have no template form and can only run natively
+    pub fn script(&self) -> Option<Script> {
+        match *self {
+            Scorer::Absolute => Some(Script {
+                groovy: "(avg - movavg).abs()",
+                painless: "Math.abs(params.avg - params.movavg)"
+            }),
+            Scorer::Percent => Some(Script {
+                groovy: "((avg - movavg) / movavg).abs() * 100",
+                painless: "Math.abs((params.avg - params.movavg) / params.movavg) * 100"
+            }),
+            Scorer::LogRatio => Some(Script {
+                groovy: "Math.log(avg / movavg).abs()",
+                painless: "Math.abs(Math.log(params.avg / params.movavg))"
+            }),
+            Scorer::ZScore | Scorer::Mad => None
+        }
+    }
+
+    /// Score `value` against the model's `expected` value and the window of `history`
+    /// it was predicted from.  Returns None when the score is undefined (e.g. a window
+    /// with no spread)
+    pub fn score(&self, value: f64, expected: f64, history: &[f64]) -> Option<f64> {
+        let score = match *self {
+            Scorer::Absolute => (value - expected).abs(),
+            Scorer::ZScore => {
+                let n = history.len() as f64;
+                let mean = history.iter().fold(0f64, |acc, v| acc + v) / n;
+                let variance = history.iter().fold(0f64, |acc, v| acc + (v - mean) * (v - mean)) / n;
+                (value - expected).abs() / variance.sqrt()
+            },
+            Scorer::Mad => {
+                let median = match percentile(history, 50.0) {
+                    Some(median) => median,
+                    None => return None
+                };
+                let deviations: Vec<f64> = history.iter().map(|v| (v - median).abs()).collect();
+                let mad = percentile(&deviations, 50.0).unwrap() * MAD_SCALE;
+                (value - median).abs() / mad
+            },
+            Scorer::Percent => ((value - expected) / expected).abs() * 100.0,
+            Scorer::LogRatio => (value / expected).ln().abs()
+        };
+
+        match score.is_finite() {
+            true => Some(score),
+            false => None
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Stopping cleanly on Ctrl-C.  Once a shutdown is requested the long-running loops
+//! (generating the timeline, change points, the hotcloud threads, streaming and
+//! following) stop at the end of the hour they are on, send what they have, save their
+//! progress to the checkpoint and return.
+//!
+//! A `Shutdown` is a handle shared by everything one run starts, so a library user (or a
+//! test) can stop one run without touching any other.  Only the signal handler is
+//! process-wide, and `install` forwards the signals it catches to a handle.
+
+use libc::{self, c_int};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::cmp;
+
+// The signal caught by the handler, or 0.  A signal handler can only reach statics
+static SIGNAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A request to stop, shared between the loops of a run.  Cloning it gives another handle
+/// on the same request
+#[derive(Clone)]
+pub struct Shutdown {
+    // The signal that asked us to stop, or 0
+    signal: Arc<AtomicUsize>
+}
+
+impl Shutdown {
+    /// A handle nobody has asked to stop yet
+    pub fn new() -> Shutdown {
+        Shutdown { signal: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// Ask the run to stop, as if it had been interrupted with Ctrl-C
+    pub fn request(&self) {
+        self.signal.compare_and_swap(0, libc::SIGINT as usize, Ordering::SeqCst);
+    }
+
+    /// Whether the run should stop
+    pub fn requested(&self) -> bool {
+        self.signal.load(Ordering::SeqCst) != 0
+    }
+
+    /// The exit status of a process that was asked to stop: 128 plus the signal, as a
+    /// shell would report it
+    pub fn status(&self) -> i32 {
+        128 + self.signal.load(Ordering::SeqCst) as i32
+    }
+
+    /// Sleep for `ms` milliseconds, or until a shutdown is requested.  Returns false if it
+    /// was
+    pub fn sleep_ms(&self, ms: u32) -> bool {
+        let mut left = ms;
+        while left > 0 && !self.requested() {
+            let step = cmp::min(left, 100);
+            thread::sleep_ms(step);
+            left -= step;
+        }
+        !self.requested()
+    }
+}
+
+/// Turn SIGINT and SIGTERM into a request on `shutdown`.  A second signal, for when
+/// stopping cleanly takes too long, exits straight away
+pub fn install(shutdown: &Shutdown) {
+    unsafe {
+        libc::signal(libc::SIGINT, handle as extern "C" fn(c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handle as extern "C" fn(c_int) as libc::sighandler_t);
+    }
+
+    // The handler can't do more than store the signal, so pass it on from a thread
+    let signal = shutdown.signal.clone();
+    thread::spawn(move || loop {
+        match SIGNAL.load(Ordering::SeqCst) {
+            0 => thread::sleep_ms(50),
+            caught => {
+                signal.compare_and_swap(0, caught, Ordering::SeqCst);
+                return;
+            }
+        }
+    });
+}
+
+extern "C" fn handle(signal: c_int) {
+    if SIGNAL.swap(signal as usize, Ordering::SeqCst) != 0 {
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
@@ -0,0 +1,188 @@
+use detect;
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use time::{Duration, PreciseTime};
+
+/// Timings and counts collected while talking to the cluster: every bulk sent, and every
+/// hour of hotcloud queries.  A `Summary` of them is printed at the end of a run, so
+/// runs against different ES configurations can be compared
+pub struct Stats {
+    started: PreciseTime,
+    collected: Mutex<Collected>
+}
+
+#[derive(Default)]
+struct Collected {
+    docs: BTreeMap<String, usize>,
+    bytes: usize,
+    // Milliseconds per successful bulk, and per hour of queries
+    bulks: Vec<f64>,
+    queries: Vec<f64>,
+    retries: usize,
+    failed_bulks: usize,
+    failed_docs: usize,
+    // When the first bulk started and the last one ended, from the start
+    first: Option<f64>,
+    last: f64
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: PreciseTime::now(),
+            collected: Mutex::new(Collected::default())
+        }
+    }
+
+    /// A bulk of `docs` documents (`bytes` long) was indexed into `index`
+    pub fn bulk(&self, index: &str, docs: usize, bytes: usize, start: PreciseTime, end: PreciseTime) {
+        let mut collected = self.collected.lock().unwrap();
+        *collected.docs.entry(index.to_owned()).or_insert(0) += docs;
+        collected.bytes += bytes;
+        collected.bulks.push(millis(start.to(end)));
+
+        let (start, end) = (seconds(self.started.to(start)), seconds(self.started.to(end)));
+        collected.first = Some(collected.first.map_or(start, |first| first.min(start)));
+        collected.last = collected.last.max(end);
+    }
+
+    /// A bulk was rejected or failed to arrive, and is being sent again
+    pub fn retry(&self) {
+        self.collected.lock().unwrap().retries += 1;
+    }
+
+    /// A bulk of `docs` documents was given up on
+    pub fn failed_bulk(&self, docs: usize) {
+        let mut collected = self.collected.lock().unwrap();
+        collected.failed_bulks += 1;
+        collected.failed_docs += docs;
+    }
+
+    /// `docs` documents of a bulk that was otherwise indexed were rejected
+    pub fn failed_docs(&self, docs: usize) {
+        self.collected.lock().unwrap().failed_docs += docs;
+    }
+
+    /// The hotcloud of an hour was queried, from `start` to `end`
+    pub fn query(&self, start: PreciseTime, end: PreciseTime) {
+        self.collected.lock().unwrap().queries.push(millis(start.to(end)));
+    }
+
+    pub fn summary(&self) -> Summary {
+        let collected = self.collected.lock().unwrap();
+        let docs = collected.docs.values().fold(0, |sum, docs| sum + docs);
+        let indexing = collected.first.map_or(0.0, |first| collected.last - first);
+        let rate = |count: usize| if indexing > 0.0 { count as f64 / indexing } else { 0.0 };
+
+        Summary {
+            elapsed: seconds(self.started.to(PreciseTime::now())),
+            indexing: indexing,
+            docs: collected.docs.clone(),
+            bytes: collected.bytes,
+            docs_per_sec: rate(docs),
+            bytes_per_sec: rate(collected.bytes),
+            bulks: Latency::of(&collected.bulks),
+            retries: collected.retries,
+            failed_bulks: collected.failed_bulks,
+            failed_docs: collected.failed_docs,
+            queries: Latency::of(&collected.queries)
+        }
+    }
+}
+
+/// What a run sent to the cluster and how long it took.  Rates are over the time spent
+/// indexing, from the start of the first bulk to the end of the last
+#[derive(RustcEncodable, Debug)]
+pub struct Summary {
+    /// Seconds since the cluster was connected to
+    pub elapsed: f64,
+    /// Seconds from the start of the first bulk to the end of the last
+    pub indexing: f64,
+    /// Documents indexed, by index
+    pub docs: BTreeMap<String, usize>,
+    pub bytes: usize,
+    pub docs_per_sec: f64,
+    pub bytes_per_sec: f64,
+    /// Milliseconds per bulk indexed
+    pub bulks: Latency,
+    /// Bulks sent again after being rejected (429/503) or failing to arrive
+    pub retries: usize,
+    /// Bulks given up on after every retry, or that can't succeed
+    pub failed_bulks: usize,
+    /// Documents lost in those bulks, or rejected from bulks that were indexed
+    pub failed_docs: usize,
+    /// Milliseconds per hour of hotcloud queries
+    pub queries: Latency
+}
+
+impl Summary {
+    /// Write the summary as JSON to `path`
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut file = try!(File::create(path));
+        try!(write!(file, "{}", json::as_pretty_json(self)));
+        writeln!(file, "")
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let docs = self.docs.values().fold(0, |sum, docs| sum + docs);
+        try!(writeln!(f, "Run took {:.1}s", self.elapsed));
+        try!(writeln!(f, "  indexed {} docs ({:.1} MB) in {:.1}s: {:.0} docs/s, {:.2} MB/s",
+                      docs, mb(self.bytes as f64), self.indexing, self.docs_per_sec, mb(self.bytes_per_sec)));
+        for (index, docs) in &self.docs {
+            try!(writeln!(f, "    {}: {} docs", index, docs));
+        }
+        try!(writeln!(f, "  bulks: {}", self.bulks));
+        try!(writeln!(f, "  retries: {}, failed bulks: {}, failed docs: {}", self.retries, self.failed_bulks, self.failed_docs));
+        write!(f, "  hotcloud queries per hour: {}", self.queries)
+    }
+}
+
+/// Percentiles of a set of timings, in milliseconds
+#[derive(RustcEncodable, Debug)]
+pub struct Latency {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64
+}
+
+impl Latency {
+    fn of(timings: &[f64]) -> Latency {
+        let percentile = |percent| detect::percentile(timings, percent).unwrap_or(0.0);
+        Latency {
+            count: timings.len(),
+            mean: if timings.len() > 0 { timings.iter().fold(0.0, |sum, t| sum + t) / timings.len() as f64 } else { 0.0 },
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+            max: percentile(100.0)
+        }
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, mean {:.1}ms, p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+               self.count, self.mean, self.p50, self.p90, self.p99, self.max)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.num_microseconds().unwrap_or(i64::max_value()) as f64 / 1000.0
+}
+
+fn seconds(duration: Duration) -> f64 {
+    millis(duration) / 1000.0
+}
+
+fn mb(bytes: f64) -> f64 {
+    bytes / 1024.0 / 1024.0
+}
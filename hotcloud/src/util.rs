@@ -1,11 +1,20 @@
 
 use ::Disruption;
-use rustc_serialize::{Encodable};
-use rustc_serialize::json::{self};
-use std::sync::Arc;
+use rustc_serialize::json::Json;
+use std::cell::RefCell;
+use std::io::Read;
 use std::sync::atomic::Ordering;
+use std::thread;
 use time::PreciseTime;
-use hyper::Client;
+use hyper::header::ContentType;
+use hyper::status::StatusCode;
+use es::{self, Cluster, Doc, Document};
+use batch::Sent;
+
+thread_local!(static BODY: RefCell<Vec<u8>> = RefCell::new(Vec::new()));
+
+// Times a rejected bulk, or one that didn't arrive, is sent again before it is given up on
+const RETRIES: u32 = 3;
 
 pub fn disruption_to_usize(d: &Option<&(Disruption, usize)>) -> usize {
     match *d {
@@ -16,25 +25,101 @@ pub fn disruption_to_usize(d: &Option<&(Disruption, usize)>) -> usize {
     }
 }
 
-pub fn send_bulk<T: Encodable>(url: &str, client: &Arc<Client>, bulk: Vec<T>) {
-    ::ACTIVE_THREADS.fetch_add(1, Ordering::SeqCst);
+/// Index `bulk`, retrying with backoff while the cluster pushes back, and record how it
+/// went in the cluster's stats
+pub fn send_bulk<T: Document>(es: &Cluster, doc: Doc, bulk: Vec<T>) -> Sent {
+    if bulk.len() == 0 {
+        return Sent { millis: None, retries: 0, rejected: 0 };
+    }
 
-    debug!("......");
-    let mut s = String::new();
+    // Every sending thread builds its bodies in the same buffer, which soon stops growing
+    BODY.with(|body| {
+        let mut body = body.borrow_mut();
+        body.clear();
+        es::write_bulk(&mut *body, &bulk).unwrap();
+        send_body(es, doc, bulk.len(), &body)
+    })
+}
+
+/// Index `bulk` like `send_bulk`, failing if it was given up on.  For results whose
+/// progress is saved, which mustn't move past a bulk that never landed
+pub fn index_bulk<T: Document>(es: &Cluster, doc: Doc, bulk: Vec<T>) -> Result<(), String> {
     let size = bulk.len();
-    for b in bulk {
-        s.push_str("{\"index\":{}}\n");
-        s.push_str(&json::encode(&b).unwrap());
-        s.push_str("\n");
+    match send_bulk(es, doc, bulk).millis {
+        None if size > 0 => Err(format!("a bulk of {} documents into {} was given up on", size, es.index(doc))),
+        _ => Ok(())
     }
+}
 
-    debug!("                      >>>>> Bulk: {} mb ({} elements)", s.len() / 1024 / 1024, size);
-    let start = PreciseTime::now();
-    let _ = client.post(url)
-                        .body(&s)
-                        .send()
-                        .unwrap();
-    let end = PreciseTime::now();
-    let _ = PreciseTime::to(&start, end);
-    ::ACTIVE_THREADS.fetch_sub(1, Ordering::SeqCst);
+/// Index the `size` documents of a bulk body that has already been written, as
+/// `send_bulk` does
+pub fn send_body(es: &Cluster, doc: Doc, size: usize, body: &[u8]) -> Sent {
+    es.in_flight.fetch_add(1, Ordering::SeqCst);
+    debug!("                      >>>>> Bulk: {} mb ({} elements)", body.len() / 1024 / 1024, size);
+    let sent = post_bulk(es, doc, size, body);
+    es.in_flight.fetch_sub(1, Ordering::SeqCst);
+    sent
+}
+
+// Send a bulk body until it is indexed or given up on
+fn post_bulk(es: &Cluster, doc: Doc, size: usize, body: &[u8]) -> Sent {
+    let url = es.doc_url(doc, "_bulk");
+    let mut attempt = 0;
+    loop {
+        let start = PreciseTime::now();
+        let response = es.client.post(&url)
+                            .header(ContentType::json())
+                            .body(body)
+                            .send();
+        let outcome = match response {
+            Ok(mut response) => {
+                let mut reply = String::new();
+                let _ = response.read_to_string(&mut reply);
+                match response.status {
+                    status if status.is_success() => Ok(reply),
+                    status @ StatusCode::TooManyRequests | status @ StatusCode::ServiceUnavailable => Err((true, status.to_string())),
+                    status => Err((false, format!("{}: {}", status, reply)))
+                }
+            },
+            Err(err) => Err((true, err.to_string()))
+        };
+        let end = PreciseTime::now();
+
+        match outcome {
+            Ok(reply) => {
+                es.stats.bulk(es.index(doc), size, body.len(), start, end);
+                let rejected = rejected_docs(&reply);
+                if rejected > 0 {
+                    warn!("{} of {} documents were rejected from a bulk into {}", rejected, size, es.index(doc));
+                    es.stats.failed_docs(rejected);
+                }
+                return Sent { millis: Some(start.to(end).num_microseconds().unwrap_or(0) as f64 / 1000.0), retries: attempt, rejected: rejected };
+            },
+            Err((true, reason)) if attempt < RETRIES => {
+                attempt += 1;
+                warn!("Bulk into {} failed ({}), retrying ({} of {})", es.index(doc), reason, attempt, RETRIES);
+                es.stats.retry();
+                thread::sleep_ms(250 << attempt);
+            },
+            Err((_, reason)) => {
+                error!("Bulk of {} documents into {} failed, giving up: {}", size, es.index(doc), reason);
+                es.stats.failed_bulk(size);
+                return Sent { millis: None, retries: attempt, rejected: 0 };
+            }
+        }
+    }
+}
+
+// The documents of an indexed bulk that failed individually
+fn rejected_docs(body: &str) -> usize {
+    let response = match Json::from_str(body) {
+        Ok(response) => response,
+        Err(_) => return 0
+    };
+    if response.find("errors").and_then(|errors| errors.as_boolean()) != Some(true) {
+        return 0;
+    }
+    response.find("items").and_then(|items| items.as_array()).map_or(0, |items| {
+        items.iter().filter(|item| item.find_path(&["index", "error"]).is_some()).count()
+    })
 }
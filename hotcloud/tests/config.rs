@@ -0,0 +1,38 @@
+//! Loading config files from disk: the bodies they point to are read and checked as the
+//! config is loaded.
+
+extern crate hotcloud;
+extern crate rustc_serialize;
+
+use hotcloud::{query, Config};
+use hotcloud::es::Dialect;
+use rustc_serialize::json::Json;
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+
+// A config.toml setting `es.query` to a template file, in a directory of its own
+fn load(name: &str, template: &str) -> Result<Config, Vec<String>> {
+    let dir = env::temp_dir().join(format!("hotcloud-config-{}", name));
+    let _ = fs::create_dir_all(&dir);
+    File::create(dir.join("hotcloud.mustache")).unwrap().write_all(template.as_bytes()).unwrap();
+    File::create(dir.join("config.toml")).unwrap().write_all(b"[es]\nquery = \"hotcloud.mustache\"\n").unwrap();
+
+    let loaded = Config::load(&dir.join("config.toml").display().to_string());
+    let _ = fs::remove_dir_all(&dir);
+    loaded.map_err(|errors| errors.into_iter().map(|err| err.field).collect())
+}
+
+#[test]
+fn mustache_templates_may_leave_tags_unquoted() {
+    let template = r#"{"size": {{size}}{{^size}}0{{/size}}, "query": {"range": {"hour": {"gte": "{{start}}", "lte": "{{{end}}}"}}}}"#;
+    let config = load("unquoted", template).unwrap();
+    assert_eq!(config.es.query, template);
+    assert_eq!(query::template(&config, Dialect::V8), Json::String(template.to_owned()));
+
+    let quoted = r#"{"query": {"range": {"hour": {"gte": "{{start}}", "lte": "{{end}}"}}}}"#;
+    let config = load("quoted", quoted).unwrap();
+    assert_eq!(query::template(&config, Dialect::V8), Json::from_str(quoted).unwrap());
+
+    assert_eq!(load("broken", r#"{"size": {{size}}"#).unwrap_err(), vec!["es.query".to_owned()]);
+}
@@ -0,0 +1,49 @@
+//! The in-process detectors against values worked out by hand, independently of the
+//! mock cluster (which computes its pipeline aggregations with the same code).
+
+extern crate hotcloud;
+
+use hotcloud::{detect, Model, Scorer};
+
+fn close(actual: Option<f64>, expected: f64) -> bool {
+    actual.map_or(false, |actual| (actual - expected).abs() < 1e-9)
+}
+
+#[test]
+fn models_predict_the_next_value_of_a_window() {
+    let window = [1.0, 2.0, 3.0];
+    assert!(close(Model::Simple.predict(&window), 2.0));
+    // (1*1 + 2*2 + 3*3) / (1 + 2 + 3)
+    assert!(close(Model::Linear.predict(&window), 14.0 / 6.0));
+    // 1, then 0.5*2 + 0.5*1 = 1.5, then 0.5*3 + 0.5*1.5 = 2.25
+    assert!(close(Model::Ewma { alpha: 0.5 }.predict(&window), 2.25));
+    assert_eq!(Model::Simple.predict(&[]), None);
+}
+
+#[test]
+fn scorers_measure_the_distance_from_the_baseline() {
+    assert!(close(Scorer::Absolute.score(5.0, 2.0, &[]), 3.0));
+    assert!(close(Scorer::Absolute.score(1.0, 2.0, &[]), 1.0));
+    assert!(close(Scorer::Percent.score(3.0, 2.0, &[]), 50.0));
+    assert!(close(Scorer::LogRatio.score(::std::f64::consts::E, 1.0, &[]), 1.0));
+    assert_eq!(Scorer::Percent.score(3.0, 0.0, &[]), None);
+}
+
+#[test]
+fn the_largest_surprise_of_a_series_skips_empty_hours() {
+    // Baselines of a 3 hour simple moving average: none, 1, (1+2)/2 and (1+2+3)/3, so the
+    // surprises are 1, 1.5 and 8
+    let averages = [Some(1.0), Some(2.0), None, Some(3.0), Some(10.0)];
+    assert!(close(detect::largest_surprise(&Model::Simple, &Scorer::Absolute, 3, &averages), 8.0));
+    assert_eq!(detect::largest_surprise(&Model::Simple, &Scorer::Absolute, 3, &[Some(1.0)]), None);
+}
+
+#[test]
+fn percentiles_pick_the_nearest_rank_and_skip_nans() {
+    let values = [4.0, 1.0, ::std::f64::NAN, 3.0, 2.0, 5.0];
+    assert_eq!(detect::percentile(&values, 0.0), Some(1.0));
+    assert_eq!(detect::percentile(&values, 50.0), Some(3.0));
+    assert_eq!(detect::percentile(&values, 90.0), Some(5.0));
+    assert_eq!(detect::percentile(&[::std::f64::NAN], 50.0), None);
+    assert_eq!(detect::percentile(&[], 50.0), None);
+}
@@ -0,0 +1,476 @@
+//! Full runs against the mock cluster in `mock`: generate a small scenario, index it,
+//! compute the hotcloud with the stored search templates, and check what ends up in the
+//! indices.  No Elasticsearch is needed.
+
+extern crate hotcloud;
+extern crate rustc_serialize;
+extern crate hyper;
+extern crate chrono;
+
+mod mock;
+
+use hotcloud::{detect, query, Checkpoint, Cluster, Config, EsSink, Generator, Reporter, Shutdown, Sink, TupleResult};
+use hotcloud::metrics::Endpoint;
+use hotcloud::query::HotcloudResult;
+use mock::MockEs;
+use hyper::Client;
+use rustc_serialize::json;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+
+// Small enough to run in seconds, big enough for the disruptions and the baseline window
+fn config(url: &str) -> Config {
+    let mut config = Config::new();
+    config.nodes = 3;
+    config.queries = 20;
+    config.metrics = 2;
+    config.hours = 96;
+    config.disruptions = 2;
+    config.threads = 2;
+    config.detect.percentiles = vec![90.0, 99.0];
+    config.detect.stats = vec!["max".to_owned(), "mean".to_owned()];
+    config.node.enabled = true;
+    config.es.url = url.to_owned();
+    config.es.bulk_size = 1000;
+    config.checkpoint.path = String::new();
+
+    assert!(config.validate().is_empty(), "invalid test config: {:?}", config.validate());
+    config
+}
+
+fn run(version: &str) -> (MockEs, Arc<Cluster>) {
+    let mock = MockEs::start(version);
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    hotcloud::run(&es, config(&mock.url), &Reporter::none(), &Shutdown::new()).unwrap();
+    (mock, es)
+}
+
+fn hotcloud_results(mock: &MockEs) -> Vec<HotcloudResult> {
+    mock.docs("hotcloud").iter()
+        .filter(|doc| doc.find("stats").is_some())
+        .map(|doc| json::decode(&doc.to_string()).unwrap())
+        .collect()
+}
+
+#[test]
+fn run_indexes_data_and_hotcloud() {
+    let (mock, _) = run("8.11.0");
+    let config = config(&mock.url);
+
+    assert_eq!(mock.docs("data").len(), config.nodes * config.queries * config.metrics * config.hours);
+
+    // The first hour has no history to be surprised against
+    let results = hotcloud_results(&mock);
+    assert_eq!(results.len(), config.metrics * (config.hours - 1));
+    for result in &results {
+        assert!(result.stats.contains_key("p90") && result.stats.contains_key("p99"));
+        assert!(result.stats.contains_key("max") && result.stats.contains_key("mean"));
+        assert_eq!(result.value, result.stats["p90"]);
+        assert!(result.top.len() > 0);
+    }
+
+    assert!(mock.docs("hotcloud_node").len() > 0);
+    assert!(mock.template("hotcloud").is_some());
+    assert!(mock.template("hotcloud_node").is_some());
+    assert_eq!(mock.unhandled(), Vec::<String>::new());
+}
+
+#[test]
+fn the_binary_runs_from_its_config_file() {
+    let mock = MockEs::start("8.11.0");
+    let config = config(&mock.url);
+    let dir = env::temp_dir().join("hotcloud-end-to-end-binary");
+    let _ = fs::create_dir_all(&dir);
+    File::create(dir.join("config.toml")).unwrap().write_all(format!(
+        "nodes = {}\nqueries = {}\nmetrics = {}\nhours = {}\ndisruptions = {}\nthreads = {}\n\
+         [detect]\npercentiles = [90.0, 99.0]\n\
+         [checkpoint]\npath = \"\"\n\
+         [summary]\npath = \"summary.json\"\n\
+         [es]\nurl = \"{}\"\nbulk_size = {}\n",
+        config.nodes, config.queries, config.metrics, config.hours, config.disruptions, config.threads,
+        mock.url, config.es.bulk_size).as_bytes()).unwrap();
+
+    // The binary is built next to the directory the test executables are in
+    let binary = env::current_exe().unwrap().parent().unwrap().parent().unwrap().join("hotcloud");
+    let output = Command::new(&binary).arg("run").current_dir(&dir).env_remove("HOTCLOUD_ENV").output().unwrap();
+    let summary = fs::metadata(dir.join("summary.json")).is_ok();
+    let _ = fs::remove_dir_all(&dir);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Run took"));
+    assert!(summary);
+    assert_eq!(mock.docs("data").len(), config.nodes * config.queries * config.metrics * config.hours);
+    assert_eq!(hotcloud_results(&mock).len(), config.metrics * (config.hours - 1));
+    assert_eq!(mock.unhandled(), Vec::<String>::new());
+}
+
+#[test]
+fn the_binary_exits_with_the_error_when_the_cluster_is_unreachable() {
+    let dir = env::temp_dir().join("hotcloud-end-to-end-unreachable");
+    let _ = fs::create_dir_all(&dir);
+    File::create(dir.join("config.toml")).unwrap().write_all(b"[es]\nurl = \"http://127.0.0.1:1\"\n").unwrap();
+
+    let binary = env::current_exe().unwrap().parent().unwrap().parent().unwrap().join("hotcloud");
+    let output = Command::new(&binary).arg("run").current_dir(&dir).env_remove("HOTCLOUD_ENV").output().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Could not reach a cluster at http://127.0.0.1:1"));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("panicked"));
+}
+
+#[test]
+fn template_matches_native_detection() {
+    let (mock, es) = run("8.11.0");
+    let config = config(&mock.url);
+    let (model, scorer) = query::detectors(&config);
+
+    let results = hotcloud_results(&mock);
+    for hour in 1..config.hours {
+        let native = detect::query_hour(&es, &config, &model, &scorer, hour).unwrap();
+        assert_eq!(native.len(), config.metrics);
+
+        for expected in native {
+            let actual = results.iter().find(|r| r.metric == expected.metric && r.hour == expected.hour)
+                .unwrap_or_else(|| panic!("no hotcloud for metric {} at {}", expected.metric, expected.hour));
+            for (stat, value) in &expected.stats {
+                assert!((actual.stats[stat] - value).abs() < 1e-6,
+                        "{} of metric {} at {}: template {} != native {}", stat, expected.metric, expected.hour, actual.stats[stat], value);
+            }
+        }
+    }
+}
+
+#[test]
+fn interrupted_runs_resume_from_the_checkpoint() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let path = env::temp_dir().join("hotcloud-end-to-end.checkpoint");
+    let resumable = || {
+        let mut config = config(&mock.url);
+        config.checkpoint.path = path.to_str().unwrap().to_owned();
+        config
+    };
+
+    // A run that got 60 hours of data out before it died, but only saved 48 of them
+    let config = resumable();
+    hotcloud::reset_indices(&es, &config);
+    Checkpoint::start(&config, 7).update(|progress| progress.generated = 48);
+    let mut sink = EsSink::new(&es, &config, &Reporter::none());
+    for hour in Generator::new(&config).seed(7).timeline().take(60) {
+        sink.send(hour.docs);
+    }
+    sink.finish().unwrap();
+
+    hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap();
+    assert!(!path.exists());
+
+    // Every hour is there once, as an uninterrupted run would have generated it
+    let key = |doc: &TupleResult| (doc.hour.clone(), doc.node, doc.query, doc.metric);
+    let mut expected: Vec<TupleResult> = Generator::new(&config).seed(7).timeline().records().collect();
+    let mut indexed: Vec<TupleResult> = mock.docs("data").iter().map(|doc| json::decode(&doc.to_string()).unwrap()).collect();
+    expected.sort_by(|a, b| key(a).cmp(&key(b)));
+    indexed.sort_by(|a, b| key(a).cmp(&key(b)));
+    assert_eq!(indexed.len(), expected.len());
+    for (doc, expected) in indexed.iter().zip(&expected) {
+        assert_eq!(key(doc), key(expected));
+        assert_eq!(doc.disruption, expected.disruption);
+        assert_eq!(doc.hit, expected.hit);
+        assert!((doc.value - expected.value).abs() < 1e-9, "{:?} != {:?}", doc, expected);
+    }
+
+    assert_eq!(hotcloud_results(&mock).len(), config.metrics * (config.hours - 1));
+}
+
+#[test]
+fn hours_that_failed_to_score_are_scored_on_resume() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let path = env::temp_dir().join("hotcloud-end-to-end-unscored.checkpoint");
+    let resumable = || {
+        let mut config = config(&mock.url);
+        config.checkpoint.path = path.to_str().unwrap().to_owned();
+        config
+    };
+
+    // Every hotcloud search fails, so no hour is saved as scored
+    mock.fail_template_searches(usize::max_value());
+    let err = hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap_err();
+    assert!(err.contains("could not be scored"), "{}", err);
+    assert!(path.exists());
+    assert_eq!(hotcloud_results(&mock).len(), 0);
+
+    mock.fail_template_searches(0);
+    hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap();
+    let config = resumable();
+    assert!(!path.exists());
+    assert_eq!(hotcloud_results(&mock).len(), config.metrics * (config.hours - 1));
+}
+
+#[test]
+fn change_points_that_failed_are_computed_on_resume() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let path = env::temp_dir().join("hotcloud-end-to-end-changepoints.checkpoint");
+    let resumable = || {
+        let mut config = config(&mock.url);
+        config.checkpoint.path = path.to_str().unwrap().to_owned();
+        config
+    };
+
+    // The first search of the change points fails, the hotcloud goes ahead regardless
+    mock.fail_searches(1);
+    let err = hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap_err();
+    assert!(err.contains("change points"), "{}", err);
+    assert!(!Checkpoint::open(&resumable()).unwrap().progress().changepoints);
+    let config = resumable();
+    assert_eq!(hotcloud_results(&mock).len(), config.metrics * (config.hours - 1));
+
+    hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn a_requested_shutdown_stops_the_run_with_its_progress_saved() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let path = env::temp_dir().join("hotcloud-end-to-end-shutdown.checkpoint");
+    let resumable = || {
+        let mut config = config(&mock.url);
+        config.checkpoint.path = path.to_str().unwrap().to_owned();
+        config.changepoint.enabled = false;
+        config
+    };
+    let hours = resumable().hours;
+
+    // The hotcloud searches fail and back off, and the shutdown comes while they do
+    mock.fail_template_searches(usize::max_value());
+    let (reporter, shutdown) = (Reporter::none(), Shutdown::new());
+    let (generated, requested) = (reporter.clone(), shutdown.clone());
+    let stopper = thread::spawn(move || {
+        while generated.generated() < hours {
+            thread::sleep_ms(10);
+        }
+        requested.request();
+    });
+    hotcloud::run(&es, resumable(), &reporter, &shutdown).unwrap();
+    stopper.join().unwrap();
+
+    let progress = Checkpoint::open(&resumable()).unwrap().progress();
+    assert_eq!(progress.generated, hours);
+    assert!(progress.batches.iter().all(|batch| batch.next < batch.end), "{:?}", progress.batches);
+    assert_eq!(hotcloud_results(&mock).len(), 0);
+
+    mock.fail_template_searches(0);
+    hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap();
+    assert!(!path.exists());
+    assert_eq!(hotcloud_results(&mock).len(), resumable().metrics * (hours - 1));
+}
+
+#[test]
+fn hours_whose_nodes_could_not_be_attributed_are_not_scored() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let mut config = config(&mock.url);
+    config.changepoint.enabled = false;
+
+    // The breakdown per node is the only plain search left
+    mock.fail_searches(usize::max_value());
+    let err = hotcloud::run(&es, config, &Reporter::none(), &Shutdown::new()).unwrap_err();
+    assert!(err.contains("could not be scored"), "{}", err);
+    assert_eq!(hotcloud_results(&mock).len(), 0);
+}
+
+#[test]
+fn checkpoints_of_another_scenario_are_refused() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let path = env::temp_dir().join("hotcloud-end-to-end-other.checkpoint");
+    let saved = || {
+        let mut config = config(&mock.url);
+        config.checkpoint.path = path.to_str().unwrap().to_owned();
+        config
+    };
+    Checkpoint::start(&saved(), 7).update(|progress| progress.generated = 48);
+
+    // Both the data and the way it is scored are part of the scenario
+    let mut config = saved();
+    config.nodes += 1;
+    let err = hotcloud::run(&es, config, &Reporter::none(), &Shutdown::new()).unwrap_err();
+    assert!(err.contains("different scenario"), "{}", err);
+
+    let mut config = saved();
+    config.detect.scorer = "zscore".to_owned();
+    let err = hotcloud::run(&es, config, &Reporter::none(), &Shutdown::new()).unwrap_err();
+    let _ = fs::remove_file(&path);
+    assert!(err.contains("different scenario"), "{}", err);
+    assert_eq!(mock.docs("data").len(), 0);
+}
+
+#[test]
+fn rejected_bulks_are_retried_and_summarised() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    mock.reject_bulks(2);
+    hotcloud::run(&es, config(&mock.url), &Reporter::none(), &Shutdown::new()).unwrap();
+    let config = config(&mock.url);
+
+    let data = config.nodes * config.queries * config.metrics * config.hours;
+    assert_eq!(mock.docs("data").len(), data);
+
+    let summary = es.stats.summary();
+    assert_eq!(summary.retries, 2);
+    assert_eq!((summary.failed_bulks, summary.failed_docs), (0, 0));
+    assert_eq!(summary.docs["data"], data);
+    assert_eq!(summary.docs["hotcloud"], hotcloud_results(&mock).len());
+    assert!(summary.bulks.count >= data / config.es.bulk_size);
+    assert_eq!(summary.queries.count, config.hours);
+    assert!(summary.docs_per_sec > 0.0 && summary.bulks.p50 <= summary.bulks.p99);
+}
+
+#[test]
+fn bulks_given_up_on_are_sent_again_on_resume() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let path = env::temp_dir().join("hotcloud-end-to-end-given-up.checkpoint");
+    let resumable = || {
+        let mut config = config(&mock.url);
+        config.checkpoint.path = path.to_str().unwrap().to_owned();
+        config
+    };
+
+    // Every bulk is rejected until it is given up on, so no hour is saved as generated
+    mock.reject_bulks(usize::max_value());
+    let err = hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap_err();
+    assert!(err.contains("could not be indexed"), "{}", err);
+    assert_eq!(Checkpoint::open(&resumable()).unwrap().progress().generated, 0);
+    assert!(es.stats.summary().failed_bulks > 0);
+
+    mock.reject_bulks(0);
+    hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap();
+    let config = resumable();
+    assert!(!path.exists());
+    assert_eq!(mock.docs("data").len(), config.nodes * config.queries * config.metrics * config.hours);
+}
+
+#[test]
+fn hours_whose_results_were_given_up_on_are_scored_on_resume() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let path = env::temp_dir().join("hotcloud-end-to-end-results-given-up.checkpoint");
+    let resumable = || {
+        let mut config = config(&mock.url);
+        config.checkpoint.path = path.to_str().unwrap().to_owned();
+        // 96 hours don't divide by 5, so the last batch has the remainder
+        config.threads = 5;
+        config
+    };
+
+    // The data lands but every hotcloud bulk is given up on, so no hour is saved as scored
+    mock.reject_bulks_into("hotcloud", usize::max_value());
+    let err = hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap_err();
+    assert!(err.contains("could not be scored"), "{}", err);
+    let batches = Checkpoint::open(&resumable()).unwrap().progress().batches;
+    assert_eq!(batches.last().unwrap().end, resumable().hours);
+    assert!(batches.iter().enumerate().all(|(i, batch)| batch.next == i * (resumable().hours / 5)), "{:?}", batches);
+
+    mock.reject_bulks_into("hotcloud", 0);
+    hotcloud::run(&es, resumable(), &Reporter::none(), &Shutdown::new()).unwrap();
+    let config = resumable();
+    assert!(!path.exists());
+    assert_eq!(hotcloud_results(&mock).len(), config.metrics * (config.hours - 1));
+}
+
+#[test]
+fn bulks_are_cut_at_bulk_bytes() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let mut config = config(&mock.url);
+    config.es.bulk_bytes = 16 * 1024;
+    let (data, bulk_size) = (config.nodes * config.queries * config.metrics * config.hours, config.es.bulk_size);
+    hotcloud::run(&es, config, &Reporter::none(), &Shutdown::new()).unwrap();
+
+    let bulks = mock.bulks("data");
+    assert_eq!(mock.docs("data").len(), data);
+    assert_eq!(bulks.iter().map(|&(docs, _)| docs).sum::<usize>(), data);
+    assert!(bulks.len() > data / bulk_size + 1, "{} bulks", bulks.len());
+    assert!(bulks.iter().all(|&(_, bytes)| bytes <= 16 * 1024), "{:?}", bulks);
+}
+
+#[test]
+fn adaptive_bulks_shrink_when_rejected() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let mut config = config(&mock.url);
+    config.es.adaptive = true;
+    config.es.target_latency = 60000;
+    let (data, bulk_size) = (config.nodes * config.queries * config.metrics * config.hours, config.es.bulk_size);
+    mock.reject_bulks(1);
+    hotcloud::run(&es, config, &Reporter::none(), &Shutdown::new()).unwrap();
+
+    let bulks = mock.bulks("data");
+    assert_eq!(mock.docs("data").len(), data);
+    assert_eq!(es.stats.summary().retries, 1);
+
+    // Every bulk but the last is full until the rejection halves them
+    let sizes: Vec<usize> = bulks[..bulks.len() - 1].iter().map(|&(docs, _)| docs).collect();
+    assert!(sizes.iter().any(|&docs| docs <= bulk_size / 2), "{:?}", sizes);
+    assert!(sizes.iter().all(|&docs| docs <= bulk_size), "{:?}", sizes);
+}
+
+#[test]
+fn metrics_endpoint_reports_progress() {
+    let mock = MockEs::start("8.11.0");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let reporter = Reporter::none();
+    let endpoint = Endpoint::start("127.0.0.1:0", &es, &reporter, Some(config(&mock.url).hours)).unwrap();
+    hotcloud::run(&es, config(&mock.url), &reporter, &Shutdown::new()).unwrap();
+    let config = config(&mock.url);
+
+    let mut metrics = String::new();
+    Client::new().get(&format!("http://{}/metrics", endpoint.address())).send().unwrap().read_to_string(&mut metrics).unwrap();
+    let value = |name: &str| metrics.lines().find(|line| line.starts_with(&format!("{} ", name)))
+                                .unwrap_or_else(|| panic!("no {} in {}", name, metrics))[name.len() + 1..].to_owned();
+
+    assert_eq!(value("hotcloud_generated_hours"), config.hours.to_string());
+    assert_eq!(value("hotcloud_timeline_hours"), config.hours.to_string());
+    assert_eq!(value("hotcloud_scored_hours"), config.hours.to_string());
+    assert_eq!(value("hotcloud_bulks_queued"), "0");
+    assert_eq!(value("hotcloud_bulks_in_flight"), "0");
+    assert_eq!(value("hotcloud_bulk_errors_total"), "0");
+    assert_eq!(value("hotcloud_indexed_docs_total{index=\"data\"}"),
+               (config.nodes * config.queries * config.metrics * config.hours).to_string());
+}
+
+#[test]
+fn queries_in_the_old_format_are_upgraded() {
+    let mock = MockEs::start("2.4.6");
+    let es = Arc::new(Cluster::connect(&mock.url).unwrap());
+    let mut config = config(&mock.url);
+    config.node.enabled = false;
+    config.es.query = include_str!("fixtures/legacy_query.json").to_owned();
+    let scored = config.metrics * (config.hours - 1);
+    hotcloud::run(&es, config, &Reporter::none(), &Shutdown::new()).unwrap();
+
+    let template = mock.template("hotcloud").unwrap();
+    assert!(template.find("template").is_none());
+    assert!(template.search("ninetieth_surprise").is_none());
+    assert_eq!(hotcloud_results(&mock).len(), scored);
+    assert_eq!(mock.unhandled(), Vec::<String>::new());
+}
+
+#[test]
+fn legacy_clusters_use_mapping_types() {
+    let (mock, _) = run("2.4.6");
+    let config = config(&mock.url);
+
+    assert_eq!(mock.indices(), vec!["data".to_owned(), "hotcloud".to_owned()]);
+    assert_eq!(mock.typed_docs("data", "data").len(), config.nodes * config.queries * config.metrics * config.hours);
+    assert_eq!(hotcloud_results(&mock).len(), config.metrics * (config.hours - 1));
+    assert!(mock.typed_docs("hotcloud", "node").len() > 0);
+    assert_eq!(mock.unhandled(), Vec::<String>::new());
+}
@@ -0,0 +1,31 @@
+//! The block Box-Muller transform the generator samples every hour with: whatever the
+//! length of the block, its values should be standard normal.
+
+extern crate hotcloud;
+extern crate rand;
+
+use hotcloud::gaussian;
+use rand::{SeedableRng, XorShiftRng};
+
+// The mean and variance of a block of `len` samples
+fn moments(len: usize) -> (f64, f64) {
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, len as u32]);
+    let mut block = vec![0.0; len];
+    gaussian::fill(&mut rng, &mut block);
+    assert!(block.iter().all(|x| x.is_finite()));
+
+    let mean = block.iter().sum::<f64>() / len as f64;
+    let variance = block.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / len as f64;
+    (mean, variance)
+}
+
+#[test]
+fn blocks_of_any_length_are_standard_normal() {
+    // The standard errors of the mean and variance of 100k samples are about 0.003 and
+    // 0.0045, so 0.02 leaves a wide margin
+    for &len in &[100000, 100001] {
+        let (mean, variance) = moments(len);
+        assert!(mean.abs() < 0.02, "mean of {} samples: {}", len, mean);
+        assert!((variance - 1.0).abs() < 0.02, "variance of {} samples: {}", len, variance);
+    }
+}
@@ -0,0 +1,716 @@
+//! A stand-in for the subset of Elasticsearch hotcloud talks to, served over HTTP from
+//! inside the test process: index create/delete, `_bulk`, `_refresh`, `_cluster/health`,
+//! stored search templates, and searches whose aggregations are evaluated in memory.
+//!
+//! Only what hotcloud sends is understood.  The pipeline aggregations run the same
+//! `Model`s and `Scorer`s as native detection, recognised from the scripts the search
+//! template is built with, and anything else is answered with a 404 and recorded so a
+//! test can check that nothing went unanswered.
+
+use chrono::NaiveDateTime;
+use hotcloud::detect;
+use hotcloud::model::Model;
+use hotcloud::score::Scorer;
+use hyper::header::Connection;
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{self, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rustc_serialize::json::Json;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+type Object = BTreeMap<String, Json>;
+
+const HOUR_MILLIS: f64 = 3600000.0;
+
+#[derive(Default)]
+struct State {
+    // Index names in creation order, and their documents with the mapping type they
+    // were indexed as (legacy clusters only)
+    indices: Vec<String>,
+    docs: HashMap<String, Vec<Stored>>,
+    // Where each document indexed with an `_id` is, by index and type/id
+    ids: HashMap<(String, String), usize>,
+    templates: HashMap<String, Json>,
+    unhandled: Vec<String>,
+    // Bulks still to turn away with a 429, as an overloaded cluster would
+    rejecting: usize,
+    // Bulks into one index still to turn away, by index
+    rejecting_into: HashMap<String, usize>,
+    // Search template requests still to fail with a 503
+    failing_templates: usize,
+    // Plain search requests still to fail with a 503
+    failing_searches: usize,
+    // The documents and bytes of every bulk indexed, by index
+    bulks: HashMap<String, Vec<(usize, usize)>>
+}
+
+// An indexed document, with its numeric fields (dates as epoch milliseconds) extracted
+// once rather than on every search
+struct Stored {
+    doc_type: Option<String>,
+    source: Json,
+    fields: HashMap<String, f64>
+}
+
+impl Stored {
+    fn new(doc_type: Option<&str>, source: Json) -> Stored {
+        let fields = source.as_object().map_or(HashMap::new(), |fields| {
+            fields.iter().filter_map(|(name, value)| number(value).map(|n| (name.clone(), n))).collect()
+        });
+        Stored {
+            doc_type: doc_type.map(|t| t.to_owned()),
+            source: source,
+            fields: fields
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<f64> {
+        self.fields.get(name).cloned()
+    }
+}
+
+struct Handler {
+    version: String,
+    state: Arc<Mutex<State>>
+}
+
+impl server::Handler for Handler {
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: Response<'a, Fresh>) {
+        let mut body = String::new();
+        let _ = req.read_to_string(&mut body);
+        let uri = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new()
+        };
+
+        let (status, reply) = handle(&mut self.state.lock().unwrap(), &self.version, &req.method, &uri, &body);
+
+        // Every request on a connection of its own, so idle pooled connections of the
+        // client can't hold on to all the worker threads
+        *res.status_mut() = status;
+        res.headers_mut().set(Connection::close());
+        let _ = res.send(reply.to_string().as_bytes());
+    }
+}
+
+/// A running mock cluster
+pub struct MockEs {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+    listening: Listening
+}
+
+impl MockEs {
+    /// Start a cluster on a free port, reporting itself as Elasticsearch `version`
+    pub fn start(version: &str) -> MockEs {
+        let state = Arc::new(Mutex::new(State::default()));
+        let handler = Handler {
+            version: version.to_owned(),
+            state: state.clone()
+        };
+
+        let listening = Server::http("127.0.0.1:0").unwrap().handle_threads(handler, 8).unwrap();
+        MockEs {
+            url: format!("http://{}", listening.socket),
+            state: state,
+            listening: listening
+        }
+    }
+
+    /// The indices that exist, in creation order
+    pub fn indices(&self) -> Vec<String> {
+        self.state.lock().unwrap().indices.clone()
+    }
+
+    /// Every document in `index`
+    pub fn docs(&self, index: &str) -> Vec<Json> {
+        let state = self.state.lock().unwrap();
+        state.docs.get(index).map_or(vec![], |docs| docs.iter().map(|doc| doc.source.clone()).collect())
+    }
+
+    /// The documents of one mapping type in `index`
+    pub fn typed_docs(&self, index: &str, doc_type: &str) -> Vec<Json> {
+        let state = self.state.lock().unwrap();
+        state.docs.get(index).map_or(vec![], |docs| {
+            docs.iter().filter(|doc| doc.doc_type.as_ref().map(|t| &**t) == Some(doc_type)).map(|doc| doc.source.clone()).collect()
+        })
+    }
+
+    /// The source of a stored search template
+    pub fn template(&self, id: &str) -> Option<Json> {
+        self.state.lock().unwrap().templates.get(id).cloned()
+    }
+
+    /// Turn away the next `bulks` bulks with 429 Too Many Requests
+    pub fn reject_bulks(&self, bulks: usize) {
+        self.state.lock().unwrap().rejecting = bulks;
+    }
+
+    /// Turn away the next `bulks` bulks into `index` alone
+    pub fn reject_bulks_into(&self, index: &str, bulks: usize) {
+        self.state.lock().unwrap().rejecting_into.insert(index.to_owned(), bulks);
+    }
+
+    /// Fail the next `searches` search template requests with 503 Service Unavailable
+    pub fn fail_template_searches(&self, searches: usize) {
+        self.state.lock().unwrap().failing_templates = searches;
+    }
+
+    /// Fail the next `searches` plain search requests with 503 Service Unavailable
+    pub fn fail_searches(&self, searches: usize) {
+        self.state.lock().unwrap().failing_searches = searches;
+    }
+
+    /// The number of documents and bytes of each bulk indexed into `index`, in order
+    pub fn bulks(&self, index: &str) -> Vec<(usize, usize)> {
+        self.state.lock().unwrap().bulks.get(index).cloned().unwrap_or(vec![])
+    }
+
+    /// Requests the mock didn't understand, as "METHOD /path"
+    pub fn unhandled(&self) -> Vec<String> {
+        self.state.lock().unwrap().unhandled.clone()
+    }
+}
+
+// Dropping a `Listening` waits for the server to stop, which it never does.  Detach it
+// instead, its threads go away with the test process
+impl Drop for MockEs {
+    fn drop(&mut self) {
+        let _ = self.listening.close();
+    }
+}
+
+fn handle(state: &mut State, version: &str, method: &Method, uri: &str, body: &str) -> (StatusCode, Json) {
+    let path = uri.split('?').next().unwrap();
+    let parts: Vec<&str> = path.split('/').filter(|p| p.len() > 0).collect();
+    let legacy = version.starts_with("1.") || version.starts_with("2.");
+
+    match (method, &parts[..]) {
+        (&Method::Get, []) => ok(object(vec![("version", object(vec![("number", Json::String(version.to_owned()))]))])),
+        (&Method::Get, ["_cluster", "health"]) => ok(object(vec![("status", Json::String("green".to_owned()))])),
+
+        // Stored templates, as scripts on modern clusters
+        (&Method::Post, ["_scripts", id]) if !legacy => match parse(body).find_path(&["script", "source"]) {
+            Some(source) => {
+                state.templates.insert(id.to_string(), source.clone());
+                acknowledged()
+            },
+            None => bad_request("no script.source")
+        },
+        (&Method::Post, ["_search", "template", id]) if legacy => match parse(body).find("template") {
+            Some(source) => {
+                state.templates.insert(id.to_string(), source.clone());
+                acknowledged()
+            },
+            None => bad_request("no template")
+        },
+        (&Method::Delete, ["_scripts", id]) | (&Method::Delete, ["_search", "template", id]) => {
+            match state.templates.remove(*id) {
+                Some(_) => acknowledged(),
+                None => missing(id)
+            }
+        },
+
+        (&Method::Put, [index]) => {
+            if state.docs.contains_key(*index) {
+                return bad_request("index already exists");
+            }
+            if let Some(field) = parse(body).find("mappings").and_then(misplaced_dynamic_templates) {
+                return bad_request(&format!("mapper_parsing_exception: dynamic_templates in [{}] must be at the mapping root", field));
+            }
+            state.indices.push(index.to_string());
+            state.docs.insert(index.to_string(), vec![]);
+            acknowledged()
+        },
+        (&Method::Delete, [index]) => match state.docs.remove(*index) {
+            Some(_) => {
+                state.indices.retain(|i| i != index);
+                state.ids.retain(|&(ref i, _), _| i != index);
+                acknowledged()
+            },
+            None => missing(index)
+        },
+        (&Method::Post, [indices, "_refresh"]) => match indices.split(',').all(|i| state.docs.contains_key(i)) {
+            true => ok(object(vec![])),
+            false => missing(indices)
+        },
+
+        (&Method::Post, [index, "_bulk"]) => bulk(state, index, None, body),
+        (&Method::Post, [index, doc_type, "_bulk"]) if legacy => bulk(state, index, Some(doc_type), body),
+
+        (&Method::Post, [_, "_search"]) | (&Method::Post, [_, _, "_search"]) if state.failing_searches > 0 => {
+            state.failing_searches -= 1;
+            unavailable()
+        },
+        (&Method::Post, [index, "_search"]) => search(state, index, None, &parse(body)),
+        (&Method::Post, [index, doc_type, "_search"]) if legacy => search(state, index, Some(doc_type), &parse(body)),
+        (&Method::Post, [index, "_search", "template"]) => search_template(state, index, None, &parse(body), legacy),
+        (&Method::Post, [index, doc_type, "_search", "template"]) if legacy => {
+            search_template(state, index, Some(doc_type), &parse(body), legacy)
+        },
+
+        _ => {
+            state.unhandled.push(format!("{} {}", method, uri));
+            (StatusCode::NotFound, object(vec![("error", Json::String(format!("no handler for {} {}", method, uri)))]))
+        }
+    }
+}
+
+// ES only takes `dynamic_templates` at the root of a mapping: find the first field that
+// has them in its own definition instead
+fn misplaced_dynamic_templates(mappings: &Json) -> Option<String> {
+    fn find(properties: &Object) -> Option<String> {
+        properties.iter().filter_map(|(name, field)| {
+            match field.find("dynamic_templates") {
+                Some(_) => Some(name.clone()),
+                None => field.find("properties").and_then(|p| p.as_object()).and_then(find)
+            }
+        }).next()
+    }
+
+    // A legacy cluster's mappings are keyed by type, a modern cluster's is a single mapping
+    let roots: Vec<&Json> = match mappings.find("properties") {
+        Some(_) => vec![mappings],
+        None => mappings.as_object().map_or(vec![], |types| types.values().collect())
+    };
+    roots.into_iter().filter_map(|root| root.find("properties").and_then(|p| p.as_object()).and_then(find)).next()
+}
+
+fn parse(body: &str) -> Json {
+    Json::from_str(body).unwrap_or(Json::Null)
+}
+
+fn ok(body: Json) -> (StatusCode, Json) {
+    (StatusCode::Ok, body)
+}
+
+fn acknowledged() -> (StatusCode, Json) {
+    ok(object(vec![("acknowledged", Json::Boolean(true))]))
+}
+
+fn missing(what: &str) -> (StatusCode, Json) {
+    (StatusCode::NotFound, object(vec![("error", Json::String(format!("{} not found", what)))]))
+}
+
+fn bad_request(reason: &str) -> (StatusCode, Json) {
+    (StatusCode::BadRequest, object(vec![("error", Json::String(reason.to_owned()))]))
+}
+
+fn unavailable() -> (StatusCode, Json) {
+    (StatusCode::ServiceUnavailable, object(vec![("error", Json::String("search_phase_execution_exception".to_owned()))]))
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+}
+
+// Index the documents of a bulk body: every document follows its action line, and
+// replaces the document with the same `_id` if there is one
+fn bulk(state: &mut State, index: &str, doc_type: Option<&str>, body: &str) -> (StatusCode, Json) {
+    let rejecting = state.rejecting_into.get(index).map_or(false, |&bulks| bulks > 0);
+    if rejecting {
+        *state.rejecting_into.get_mut(index).unwrap() -= 1;
+    }
+    if rejecting || state.rejecting > 0 {
+        if !rejecting {
+            state.rejecting -= 1;
+        }
+        return (StatusCode::TooManyRequests, object(vec![("error", Json::String("es_rejected_execution_exception".to_owned()))]));
+    }
+
+    let (docs, ids) = match state.docs.get_mut(index) {
+        Some(docs) => (docs, &mut state.ids),
+        None => return missing(index)
+    };
+
+    let lines: Vec<&str> = body.lines().filter(|l| l.trim().len() > 0).collect();
+    state.bulks.entry(index.to_owned()).or_insert(vec![]).push((lines.len() / 2, body.len()));
+    for pair in lines.chunks(2) {
+        if pair.len() < 2 {
+            continue;
+        }
+
+        let doc = Stored::new(doc_type, parse(pair[1]));
+        let id = parse(pair[0]).find_path(&["index", "_id"]).and_then(|id| id.as_string().map(|id| id.to_owned()));
+        match id {
+            Some(id) => {
+                let key = (index.to_owned(), format!("{}/{}", doc_type.unwrap_or("_doc"), id));
+                match ids.get(&key).cloned() {
+                    Some(position) => docs[position] = doc,
+                    None => {
+                        ids.insert(key, docs.len());
+                        docs.push(doc);
+                    }
+                }
+            },
+            None => docs.push(doc)
+        }
+    }
+    ok(object(vec![("errors", Json::Boolean(false)), ("items", Json::Array(vec![]))]))
+}
+
+fn search_template(state: &mut State, index: &str, doc_type: Option<&str>, body: &Json, legacy: bool) -> (StatusCode, Json) {
+    if state.failing_templates > 0 {
+        state.failing_templates -= 1;
+        return unavailable();
+    }
+
+    let id = match legacy {
+        true => body.find_path(&["template", "id"]),
+        false => body.find("id")
+    };
+    // Templates are JSON, or mustache source text when they only make JSON once rendered
+    let source = match id.and_then(|id| id.as_string()).and_then(|id| state.templates.get(id)) {
+        Some(&Json::String(ref text)) => text.clone(),
+        Some(source) => source.to_string(),
+        None => return missing("template")
+    };
+
+    // Only plain variables are rendered: hotcloud's own templates have nothing else
+    let mut rendered = source;
+    if let Some(params) = body.find("params").and_then(|p| p.as_object()) {
+        for (name, value) in params {
+            let value = match *value {
+                Json::String(ref s) => s.clone(),
+                ref other => other.to_string()
+            };
+            rendered = rendered.replace(&format!("{{{{{}}}}}", name), &value);
+        }
+    }
+
+    search(state, index, doc_type, &parse(&rendered))
+}
+
+fn search(state: &State, index: &str, doc_type: Option<&str>, body: &Json) -> (StatusCode, Json) {
+    let docs = match state.docs.get(index) {
+        Some(docs) => docs,
+        None => return missing(index)
+    };
+
+    let filters = filters(body.find("query"));
+    let docs: Vec<&Stored> = docs.iter()
+        .filter(|doc| doc_type.is_none() || doc.doc_type.as_ref().map(|t| &**t) == doc_type)
+        .filter(|doc| filters.iter().all(|f| f.matches(doc)))
+        .collect();
+
+    let mut response = vec![
+        ("timed_out", Json::Boolean(false)),
+        ("hits", object(vec![("total", Json::U64(docs.len() as u64)), ("hits", Json::Array(vec![]))]))
+    ];
+    if let Some(aggs) = body.find("aggs").and_then(|a| a.as_object()) {
+        response.push(("aggregations", Json::Object(aggregate(&docs, aggs))));
+    }
+    ok(object(response))
+}
+
+// The filters of a `bool` query, or of a legacy `filtered` one
+fn filters(query: Option<&Json>) -> Vec<Filter> {
+    let query = match query {
+        Some(query) => query,
+        None => return vec![]
+    };
+
+    let filters = match query.find_path(&["bool", "filter"]).and_then(|f| f.as_array()) {
+        Some(filters) => filters.clone(),
+        None => match query.find_path(&["filtered", "filter"]) {
+            Some(filter) => match filter.find_path(&["bool", "must"]).and_then(|m| m.as_array()) {
+                Some(must) => must.clone(),
+                None => vec![filter.clone()]
+            },
+            None => vec![]
+        }
+    };
+    filters.iter().flat_map(Filter::parse).collect()
+}
+
+// A filter with its bounds and values already converted to numbers
+enum Filter {
+    Range { field: String, gte: Option<f64>, lte: Option<f64> },
+    Terms { field: String, values: Vec<f64> }
+}
+
+impl Filter {
+    fn parse(filter: &Json) -> Vec<Filter> {
+        if let Some(range) = filter.find("range").and_then(|r| r.as_object()) {
+            return range.iter().map(|(field, bounds)| Filter::Range {
+                field: field.clone(),
+                gte: bounds.find("gte").and_then(number),
+                lte: bounds.find("lte").and_then(number)
+            }).collect();
+        }
+        if let Some(terms) = filter.find("terms").and_then(|t| t.as_object()) {
+            return terms.iter().map(|(field, values)| Filter::Terms {
+                field: field.clone(),
+                values: values.as_array().map_or(vec![], |values| values.iter().filter_map(number).collect())
+            }).collect();
+        }
+        panic!("mock ES: unsupported filter {}", filter)
+    }
+
+    fn matches(&self, doc: &Stored) -> bool {
+        match *self {
+            Filter::Range { ref field, gte, lte } => doc.field(field).map_or(false, |value| {
+                gte.map_or(true, |gte| value >= gte) && lte.map_or(true, |lte| value <= lte)
+            }),
+            Filter::Terms { ref field, ref values } => doc.field(field).map_or(false, |value| values.contains(&value))
+        }
+    }
+}
+
+// Numbers as they are, dates as epoch milliseconds
+fn number(value: &Json) -> Option<f64> {
+    match *value {
+        Json::String(ref s) => NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S").ok().map(|d| d.timestamp() as f64 * 1000.0),
+        ref other => other.as_f64()
+    }
+}
+
+// The name and body of an aggregation, e.g. ("terms", {"field": ...})
+fn kind(agg: &Json) -> Option<(&str, &Json)> {
+    agg.as_object().and_then(|agg| agg.iter().find(|&(k, _)| k != "aggs").map(|(k, v)| (&**k, v)))
+}
+
+fn string<'a>(params: &'a Json, key: &str) -> &'a str {
+    params.find(key).and_then(|v| v.as_string()).unwrap_or_else(|| panic!("mock ES: no {} in {}", key, params))
+}
+
+fn aggregate(docs: &[&Stored], aggs: &Object) -> Object {
+    let mut results = Object::new();
+
+    for (name, agg) in aggs {
+        let sub = agg.find("aggs").and_then(|a| a.as_object());
+        let result = match kind(agg) {
+            Some(("terms", params)) => terms(docs, params, sub),
+            Some(("date_histogram", params)) => histogram(docs, params, sub),
+            Some((metric @ "avg", params)) | Some((metric @ "min", params)) | Some((metric @ "max", params)) => {
+                let values: Vec<f64> = docs.iter().filter_map(|doc| doc.field(string(params, "field"))).collect();
+                object(vec![("value", optional(summary(metric, &values)))])
+            },
+            _ => continue
+        };
+        results.insert(name.clone(), result);
+    }
+
+    // Sibling pipelines, over the buckets of the aggregations next to them
+    for (name, agg) in aggs {
+        let result = match kind(agg) {
+            Some(("max_bucket", params)) => object(vec![("value", optional(summary("max", &sibling_values(&results, params))))]),
+            Some(("avg_bucket", params)) => object(vec![("value", optional(summary("avg", &sibling_values(&results, params))))]),
+            Some(("percentiles_bucket", params)) => {
+                let values = sibling_values(&results, params);
+                let percents = params.find("percents").and_then(|p| p.as_array()).cloned().unwrap_or(vec![]);
+                object(vec![("values", Json::Object(percents.iter().filter_map(|p| p.as_f64()).map(|p| {
+                    (format!("{:?}", p), optional(detect::percentile(&values, p)))
+                }).collect()))])
+            },
+            _ => continue
+        };
+        results.insert(name.clone(), result);
+    }
+
+    results
+}
+
+fn summary(metric: &str, values: &[f64]) -> Option<f64> {
+    if values.len() == 0 {
+        return None;
+    }
+    match metric {
+        "min" => Some(values.iter().fold(values[0], |acc, &v| acc.min(v))),
+        "max" => Some(values.iter().fold(values[0], |acc, &v| acc.max(v))),
+        _ => Some(values.iter().fold(0f64, |acc, v| acc + v) / values.len() as f64)
+    }
+}
+
+fn optional(value: Option<f64>) -> Json {
+    value.map_or(Json::Null, Json::F64)
+}
+
+// The values a sibling pipeline's `buckets_path` points at: "series.surprise" or
+// "queries>largest_surprise" is the `surprise`/`largest_surprise` of every bucket of
+// `series`/`queries`.  Buckets without a value are skipped
+fn sibling_values(results: &Object, params: &Json) -> Vec<f64> {
+    let path = string(params, "buckets_path");
+    let split = path.find(|c| c == '>' || c == '.').unwrap_or_else(|| panic!("mock ES: unsupported buckets_path {}", path));
+    let (agg, metric) = (&path[..split], &path[split + 1..]);
+
+    let buckets = results.get(agg).and_then(|a| a.find("buckets")).and_then(|b| b.as_array()).cloned().unwrap_or(vec![]);
+    buckets.iter().filter_map(|bucket| bucket_value(bucket, metric)).collect()
+}
+
+fn bucket_value(bucket: &Json, path: &str) -> Option<f64> {
+    match path {
+        "_count" => bucket.find("doc_count").and_then(|c| c.as_f64()),
+        _ => bucket.find(path).and_then(|agg| agg.find("value")).and_then(|v| v.as_f64())
+    }
+}
+
+// Only integer fields (metric, query, node) are ever bucketed by hotcloud
+fn terms(docs: &[&Stored], params: &Json, sub: Option<&Object>) -> Json {
+    let field = string(params, "field");
+    let size = params.find("size").and_then(|s| s.as_u64()).unwrap_or(10) as usize;
+
+    let mut groups: BTreeMap<i64, Vec<&Stored>> = BTreeMap::new();
+    for doc in docs {
+        if let Some(key) = doc.field(field) {
+            groups.entry(key as i64).or_insert(vec![]).push(*doc);
+        }
+    }
+
+    // Most documents first, ties in key order (the sort is stable)
+    let mut groups: Vec<(i64, Vec<&Stored>)> = groups.into_iter().collect();
+    groups.sort_by(|a, b| b.1.len().cmp(&a.1.len()));
+
+    let other: usize = groups.iter().skip(size).map(|g| g.1.len()).sum();
+    let buckets = groups.into_iter().take(size).map(|(key, group)| {
+        let mut bucket = sub.map_or(Object::new(), |sub| aggregate(&group, sub));
+        bucket.insert("key".to_owned(), Json::I64(key));
+        bucket.insert("doc_count".to_owned(), Json::U64(group.len() as u64));
+        Json::Object(bucket)
+    }).collect();
+
+    object(vec![
+        ("doc_count_error_upper_bound", Json::U64(0)),
+        ("sum_other_doc_count", Json::U64(other as u64)),
+        ("buckets", Json::Array(buckets))
+    ])
+}
+
+// Hourly buckets from the first to the last document, empty hours included
+fn histogram(docs: &[&Stored], params: &Json, sub: Option<&Object>) -> Json {
+    let field = string(params, "field");
+    let hours: Vec<(i64, &Stored)> = docs.iter()
+        .filter_map(|doc| doc.field(field).map(|ms| ((ms / HOUR_MILLIS).floor() as i64, *doc)))
+        .collect();
+
+    let mut buckets: Vec<Object> = vec![];
+    if let (Some(first), Some(last)) = (hours.iter().map(|h| h.0).min(), hours.iter().map(|h| h.0).max()) {
+        for hour in first..last + 1 {
+            let group: Vec<&Stored> = hours.iter().filter(|h| h.0 == hour).map(|h| h.1).collect();
+            let mut bucket = sub.map_or(Object::new(), |sub| aggregate(&group, sub));
+            let key = hour as f64 * HOUR_MILLIS;
+            bucket.insert("key".to_owned(), Json::U64(key as u64));
+            bucket.insert("key_as_string".to_owned(),
+                          Json::String(NaiveDateTime::from_timestamp(hour * 3600, 0).format("%Y-%m-%dT%H:%M:%S").to_string()));
+            bucket.insert("doc_count".to_owned(), Json::U64(group.len() as u64));
+            buckets.push(bucket);
+        }
+    }
+
+    // Parent pipelines, across the buckets: the moving averages first, since the
+    // bucket scripts read them
+    if let Some(sub) = sub {
+        for (name, agg) in sub {
+            match kind(agg) {
+                Some(("moving_fn", params)) | Some(("moving_avg", params)) => moving(&mut buckets, name, agg, params),
+                _ => {}
+            }
+        }
+        for (name, agg) in sub {
+            if let Some(("bucket_script", params)) = kind(agg) {
+                bucket_script(&mut buckets, name, params);
+            }
+        }
+    }
+
+    object(vec![("buckets", Json::Array(buckets.into_iter().map(Json::Object).collect()))])
+}
+
+// Predict each bucket's value from the previous `window` ones.  Empty buckets are
+// skipped, neither getting a prediction nor joining the window
+fn moving(buckets: &mut Vec<Object>, name: &str, agg: &Json, params: &Json) {
+    let path = string(params, "buckets_path");
+    let window = params.find("window").and_then(|w| w.as_u64()).unwrap_or(5) as usize;
+    let model = match kind(agg) {
+        Some(("moving_avg", _)) => moving_avg_model(params),
+        _ => moving_fn_model(string(params, "script"))
+    };
+
+    let mut history: Vec<f64> = Vec::with_capacity(window);
+    for bucket in buckets.iter_mut() {
+        let value = match bucket_value(&Json::Object(bucket.clone()), path) {
+            Some(value) => value,
+            None => continue
+        };
+        bucket.insert(name.to_owned(), object(vec![("value", optional(model.predict(&history)))]));
+
+        if history.len() == window {
+            history.remove(0);
+        }
+        history.push(value);
+    }
+}
+
+// The model of a legacy `moving_avg` aggregation
+fn moving_avg_model(params: &Json) -> Model {
+    let setting = |name: &str| params.find_path(&["settings", name]).and_then(|s| s.as_f64()).unwrap_or(0.0);
+    match params.find("model").and_then(|m| m.as_string()).unwrap_or("simple") {
+        "simple" => Model::Simple,
+        "linear" => Model::Linear,
+        "ewma" => Model::Ewma { alpha: setting("alpha") },
+        "holt" => Model::Holt { alpha: setting("alpha"), beta: setting("beta") },
+        "holt_winters" => Model::HoltWinters {
+            alpha: setting("alpha"),
+            beta: setting("beta"),
+            gamma: setting("gamma"),
+            period: setting("period") as usize,
+            multiplicative: params.find_path(&["settings", "type"]).and_then(|t| t.as_string()) == Some("mult")
+        },
+        other => panic!("mock ES: unknown moving_avg model {}", other)
+    }
+}
+
+// The model behind a `moving_fn` script, from the `MovingFunctions` call it makes
+fn moving_fn_model(script: &str) -> Model {
+    let call = script.find("MovingFunctions.").map(|i| &script[i + "MovingFunctions.".len()..])
+        .unwrap_or_else(|| panic!("mock ES: unsupported moving_fn script {}", script));
+    let (function, rest) = call.split_at(call.find('(').unwrap());
+    let args: Vec<&str> = rest[1..rest.find(')').unwrap()].split(',').map(|a| a.trim()).skip(1).collect();
+    let arg = |i: usize| args[i].parse::<f64>().unwrap();
+
+    match function {
+        "unweightedAvg" => Model::Simple,
+        "linearWeightedAvg" => Model::Linear,
+        "ewma" => Model::Ewma { alpha: arg(0) },
+        "holt" => Model::Holt { alpha: arg(0), beta: arg(1) },
+        "holtWinters" => Model::HoltWinters {
+            alpha: arg(0),
+            beta: arg(1),
+            gamma: arg(2),
+            period: arg(3) as usize,
+            multiplicative: args[4] == "true"
+        },
+        other => panic!("mock ES: unsupported moving_fn function {}", other)
+    }
+}
+
+// Score every bucket with both an `avg` and a `movavg`, recognising the scorer from
+// its script
+fn bucket_script(buckets: &mut Vec<Object>, name: &str, params: &Json) {
+    let script = string(params, "script");
+    let scorer = [Scorer::Absolute, Scorer::Percent, Scorer::LogRatio].iter()
+        .find(|scorer| scorer.script().map_or(false, |s| s.painless == script || s.groovy == script))
+        .cloned()
+        .unwrap_or_else(|| panic!("mock ES: unsupported bucket_script {}", script));
+
+    let paths = params.find("buckets_path").and_then(|p| p.as_object()).cloned().unwrap_or(Object::new());
+    let path = |var: &str| paths.get(var).and_then(|p| p.as_string()).map(|p| p.to_owned())
+        .unwrap_or_else(|| panic!("mock ES: bucket_script without a {} path", var));
+    let (avg, movavg) = (path("avg"), path("movavg"));
+
+    for bucket in buckets.iter_mut() {
+        let values = {
+            let json = Json::Object(bucket.clone());
+            (bucket_value(&json, &avg), bucket_value(&json, &movavg))
+        };
+        if let (Some(avg), Some(movavg)) = values {
+            if let Some(score) = scorer.score(avg, movavg, &[]) {
+                bucket.insert(name.to_owned(), object(vec![("value", Json::F64(score))]));
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! The generated timeline only depends on the config and the seed: not on how many
+//! threads generate it, nor on the hour it starts from.  Its documents are written into
+//! bulk bodies exactly as `json::encode` would.
+
+extern crate hotcloud;
+extern crate rustc_serialize;
+
+use hotcloud::{es, Config, Disruption, Generator, TupleResult};
+use hotcloud::es::Document;
+use rustc_serialize::json;
+
+fn config(threads: usize) -> Config {
+    let mut config = Config::new();
+    config.nodes = 5;
+    config.queries = 20;
+    config.metrics = 3;
+    config.hours = 96;
+    config.threads = threads;
+    config
+}
+
+fn key(doc: &TupleResult) -> (String, usize, usize, usize, f64, usize) {
+    (doc.hour.to_string(), doc.node, doc.query, doc.metric, doc.value, doc.disruption)
+}
+
+#[test]
+fn timelines_are_the_same_on_any_number_of_threads() {
+    let expected: Vec<_> = Generator::new(&config(1)).seed(11).timeline().records().map(|doc| key(&doc)).collect();
+    assert_eq!(expected.len(), 5 * 20 * 3 * 96);
+
+    for threads in 2..5 {
+        let config = config(threads);
+        let generated: Vec<_> = Generator::new(&config).seed(11).timeline().records().map(|doc| key(&doc)).collect();
+        assert!(generated == expected, "{} threads generated a different timeline", threads);
+
+        let resumed: Vec<_> = Generator::new(&config).seed(11).timeline_from(50).records().map(|doc| key(&doc)).collect();
+        assert!(resumed[..] == expected[50 * 5 * 20 * 3..], "{} threads generated a different timeline from hour 50", threads);
+    }
+}
+
+#[test]
+fn disrupted_hours_flag_every_tuple_and_hit_only_the_changed_ones() {
+    let config = config(1);
+    let mut disrupted = 0;
+    for hour in Generator::new(&config).seed(11).timeline() {
+        for doc in &hour.docs {
+            let hit = match hour.disruption {
+                None => false,
+                Some((Disruption::Node(node), _)) => doc.node == node,
+                Some((Disruption::Query(ref queries), _)) => queries.contains(&doc.query),
+                Some((Disruption::Metric(ref metrics), _)) => metrics.contains(&doc.metric)
+            };
+            assert_eq!(doc.disruption > 0, hour.disruption.is_some(), "hour {} {:?}: {:?}", hour.hour, hour.disruption, doc);
+            assert_eq!(doc.hit, if hit { doc.disruption } else { 0 }, "hour {} {:?}: {:?}", hour.hour, hour.disruption, doc);
+            disrupted += hit as usize;
+        }
+    }
+    assert!(disrupted > 0);
+}
+
+#[test]
+fn documents_are_written_as_json_encode_writes_them() {
+    let config = config(2);
+    let mut docs: Vec<TupleResult> = Generator::new(&config).seed(11).timeline().take(2).flat_map(|hour| hour.docs).collect();
+    for &value in &[0.0, 30.0, -12.5, 1e21, 0.1 + 0.2, ::std::f64::NAN] {
+        docs.push(TupleResult { value: value, ..docs[0].clone() });
+    }
+
+    for doc in &docs {
+        let (mut written, mut id) = (vec![], vec![]);
+        doc.write_json(&mut written).unwrap();
+        doc.write_id(&mut id).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), json::encode(doc).unwrap());
+        assert_eq!(String::from_utf8(id).unwrap(), doc.id());
+    }
+
+    let mut body = vec![];
+    es::write_bulk(&mut body, &docs[..2]).unwrap();
+    assert_eq!(String::from_utf8(body).unwrap(),
+               format!("{{\"index\":{{\"_id\":\"{}\"}}}}\n{}\n{{\"index\":{{\"_id\":\"{}\"}}}}\n{}\n",
+                       docs[0].id(), json::encode(&docs[0]).unwrap(), docs[1].id(), json::encode(&docs[1]).unwrap()));
+}